@@ -0,0 +1,54 @@
+//! Compares record-by-record processing with `process_batch`, which coalesces runs
+//! of consecutive deposits for the same client.
+
+use accounts_manager::{PaymentsEngine, TransactionRecord, TransactionType};
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use rust_decimal::Decimal;
+
+/// `runs` runs of `run_len` deposits each, cycling through 1000 clients.
+fn deposit_runs(runs: u32, run_len: u32) -> Vec<TransactionRecord> {
+    (0..runs * run_len)
+        .map(|tx| TransactionRecord {
+            r#type: TransactionType::Deposit,
+            client: (tx / run_len % 1000) as u16,
+            tx,
+            amount: Some(Decimal::new(i64::from(tx % 10_000) + 1, 2)),
+        })
+        .collect()
+}
+
+fn bench_deposit_runs(c: &mut Criterion) {
+    let mut group = c.benchmark_group("deposit_runs");
+    for run_len in [1, 8, 64] {
+        let records = deposit_runs(100_000 / run_len, run_len);
+
+        group.bench_function(format!("process/run_len={run_len}"), |b| {
+            b.iter_batched(
+                || records.clone(),
+                |records| {
+                    let mut engine = PaymentsEngine::new();
+                    for record in records {
+                        let _ = engine.process(record);
+                    }
+                    black_box(engine)
+                },
+                BatchSize::LargeInput,
+            )
+        });
+
+        group.bench_function(format!("process_batch/run_len={run_len}"), |b| {
+            b.iter_batched(
+                PaymentsEngine::new,
+                |mut engine| {
+                    black_box(engine.process_batch(&records));
+                    engine
+                },
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_deposit_runs);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+//! Compares writing the largest possible account map (one account per `u16` client
+//! ID) to a file directly and through `BufWriter`s of different sizes, as
+//! `--output-buffer` does.
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use accounts_manager::{PaymentsEngine, TransactionRecord, TransactionType};
+use criterion::{criterion_group, criterion_main, Criterion};
+use rust_decimal::Decimal;
+
+fn every_client() -> PaymentsEngine {
+    let mut engine = PaymentsEngine::new();
+    for client in 0..=u16::MAX {
+        engine
+            .process(TransactionRecord {
+                r#type: TransactionType::Deposit,
+                client,
+                tx: client.into(),
+                amount: Some(Decimal::new(i64::from(client) * 10_000 + 1234, 4)),
+            })
+            .unwrap();
+    }
+    engine
+}
+
+fn bench_output_buffer(c: &mut Criterion) {
+    let engine = every_client();
+    let path = std::env::temp_dir().join("accounts_manager_output_buffer_bench.csv");
+
+    let mut group = c.benchmark_group("output_buffer");
+    group.bench_function("unbuffered", |b| {
+        b.iter(|| engine.write_output(File::create(&path).unwrap()).unwrap())
+    });
+    for capacity in [64 * 1024, 1024 * 1024, 8 * 1024 * 1024] {
+        group.bench_function(format!("buffered/{capacity}"), |b| {
+            b.iter(|| {
+                let mut file = BufWriter::with_capacity(capacity, File::create(&path).unwrap());
+                engine.write_output(&mut file).unwrap();
+                file.flush().unwrap();
+            })
+        });
+    }
+    group.finish();
+    let _ = std::fs::remove_file(&path);
+}
+
+criterion_group!(benches, bench_output_buffer);
+criterion_main!(benches);
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+
+use rust_decimal::prelude::ToPrimitive;
+use rust_decimal::Decimal;
+
+use crate::types::{TransactionRecord, TransactionType};
+
+/// A deposit whose amount sits unusually far from the running mean.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub client: u16,
+    pub tx: u32,
+    pub amount: Decimal,
+    /// Distance from the mean in standard deviations at the time the deposit was seen.
+    pub deviations: f64,
+}
+
+/// Flags deposits more than `threshold` standard deviations from the mean of the
+/// deposits seen before them. Mean and variance are kept with Welford's algorithm, so
+/// nothing is buffered. Flagged deposits are still folded into the statistics.
+pub struct AmountAnomalyDetector {
+    threshold: f64,
+    count: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl AmountAnomalyDetector {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+        }
+    }
+
+    pub fn observe(&mut self, record: &TransactionRecord) -> Option<Anomaly> {
+        if record.r#type != TransactionType::Deposit {
+            return None;
+        }
+        let amount = record.amount?;
+        let value = amount.to_f64()?;
+
+        let anomaly = self.deviations(value).and_then(|deviations| {
+            (deviations > self.threshold).then_some(Anomaly {
+                client: record.client,
+                tx: record.tx,
+                amount,
+                deviations,
+            })
+        });
+
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+
+        anomaly
+    }
+
+    /// Distance of `value` from the current mean in sample standard deviations, once
+    /// there are at least two samples with non-zero spread.
+    fn deviations(&self, value: f64) -> Option<f64> {
+        if self.count < 2 {
+            return None;
+        }
+        let stddev = (self.m2 / (self.count - 1) as f64).sqrt();
+        (stddev > 0.0).then(|| (value - self.mean).abs() / stddev)
+    }
+}
+
+/// A client whose activity exceeded the bounds of a [`ClientActivityMonitor`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActivityAnomaly {
+    pub client: u16,
+    /// Rows seen for the client.
+    pub transactions: u64,
+    /// Difference between the highest and lowest total the client's account reached.
+    pub swing: Decimal,
+}
+
+/// Range of one client's activity seen so far.
+#[derive(Debug, Clone, Copy)]
+struct Activity {
+    transactions: u64,
+    low: Decimal,
+    high: Decimal,
+}
+
+/// Flags clients with more rows, or a wider swing in their total balance, than a
+/// single client plausibly has in one input. Such clients often mean two datasets with
+/// overlapping client IDs were concatenated. A bound of `None` is not checked.
+pub struct ClientActivityMonitor {
+    max_transactions: Option<u64>,
+    max_swing: Option<Decimal>,
+    clients: HashMap<u16, Activity>,
+}
+
+impl ClientActivityMonitor {
+    pub fn new(max_transactions: Option<u64>, max_swing: Option<Decimal>) -> Self {
+        Self {
+            max_transactions,
+            max_swing,
+            clients: HashMap::new(),
+        }
+    }
+
+    /// Count a row for `client`, whose account total went from `before` to `after`.
+    /// The range starts at `before` on the client's first row, so an account seeded
+    /// with an opening balance swings from that balance rather than from zero.
+    pub fn observe(&mut self, client: u16, before: Decimal, after: Decimal) {
+        let activity = self.clients.entry(client).or_insert(Activity {
+            transactions: 0,
+            low: before,
+            high: before,
+        });
+        activity.transactions += 1;
+        activity.low = activity.low.min(after);
+        activity.high = activity.high.max(after);
+    }
+
+    /// Every client past either bound, sorted by client.
+    pub fn anomalies(&self) -> Vec<ActivityAnomaly> {
+        let mut anomalies: Vec<ActivityAnomaly> = self
+            .clients
+            .iter()
+            .map(|(&client, activity)| ActivityAnomaly {
+                client,
+                transactions: activity.transactions,
+                swing: activity.high - activity.low,
+            })
+            .filter(|anomaly| {
+                self.max_transactions
+                    .is_some_and(|max| anomaly.transactions > max)
+                    || self.max_swing.is_some_and(|max| anomaly.swing > max)
+            })
+            .collect();
+        anomalies.sort_unstable_by_key(|anomaly| anomaly.client);
+        anomalies
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    /// Deposit for client 1.
+    fn deposit(tx: u32, amount: &str) -> TransactionRecord {
+        TransactionRecord {
+            r#type: TransactionType::Deposit,
+            client: 1,
+            tx,
+            amount: Some(Decimal::from_str(amount).unwrap()),
+        }
+    }
+
+    #[test]
+    fn flags_only_the_outlier_deposit() {
+        let mut detector = AmountAnomalyDetector::new(3.0);
+        let amounts = [
+            "10.0", "12.0", "9.5", "11.0", "10.5", "9.0", "5000.0", "10.0",
+        ];
+
+        let flagged: Vec<Anomaly> = (1..)
+            .zip(amounts)
+            .filter_map(|(tx, amount)| detector.observe(&deposit(tx, amount)))
+            .collect();
+
+        assert_eq!(flagged.len(), 1);
+        assert_eq!(flagged[0].tx, 7);
+        assert_eq!(flagged[0].amount, Decimal::from_str("5000.0").unwrap());
+    }
+
+    #[test]
+    fn flags_clients_past_either_bound() {
+        let mut monitor = ClientActivityMonitor::new(Some(3), Some(Decimal::from(100)));
+        // Client 1 behaves; client 2 swings from a large balance to nothing; client 3 has
+        // too many rows.
+        // Feed each client's successive totals as (before, after) pairs
+        let observe = |monitor: &mut ClientActivityMonitor, client, totals: &[&str]| {
+            let totals: Vec<Decimal> = totals
+                .iter()
+                .map(|t| Decimal::from_str(t).unwrap())
+                .collect();
+            for pair in totals.windows(2) {
+                monitor.observe(client, pair[0], pair[1]);
+            }
+        };
+        observe(&mut monitor, 1, &["0", "10", "20", "15"]);
+        observe(&mut monitor, 2, &["0", "500", "0"]);
+        observe(&mut monitor, 3, &["0", "1", "2", "3", "4"]);
+        // Client 4 starts from an opening balance, so it has barely moved
+        observe(&mut monitor, 4, &["1000", "1010", "990"]);
+
+        assert_eq!(
+            monitor.anomalies(),
+            [
+                ActivityAnomaly {
+                    client: 2,
+                    transactions: 2,
+                    swing: Decimal::from(500),
+                },
+                ActivityAnomaly {
+                    client: 3,
+                    transactions: 4,
+                    swing: Decimal::from(4),
+                },
+            ]
+        );
+    }
+}
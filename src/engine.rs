@@ -1,155 +1,3340 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::io;
+use std::num::NonZeroUsize;
+use std::sync::mpsc;
 
-use crate::error::EngineError;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::error::{EngineError, TransactionRejection};
+use crate::store::TxStore;
 use crate::types::{
-    ClientAccount, OutputRecord, StoredTransaction, TransactionRecord, TransactionType,
+    AccountSnapshot, ChargebackEvent, ClientAccount, FormattedRecord, LedgerEvent, LockRecord,
+    NetRecord, OpeningBalance, OutputFormat, OutputOptions, OutputRecord, ReleasedRemainderPolicy,
+    ResolutionOutcome, ResolutionRecord, StoredKind, StoredTransaction, TransactionRecord,
+    TransactionType, WithdrawalPolicy,
 };
 
-/// Maintains client accounts and stored deposit transactions for dispute lookups.
-pub struct PaymentsEngine {
-    clients: HashMap<u16, ClientAccount>,
-    transactions: HashMap<u32, StoredTransaction>,
+/// Records buffered per shard by [`PaymentsEngine::process_parallel`] before the router
+/// blocks, bounding memory when workers fall behind.
+const PARALLEL_CHANNEL_CAPACITY: usize = 1024;
+
+/// Estimated memory taken by one stored transaction: its key and value in the store plus
+/// its place in the eviction order. Ignores the store's own overhead.
+const STORED_ENTRY_BYTES: usize =
+    2 * std::mem::size_of::<u32>() + std::mem::size_of::<StoredTransaction>();
+
+/// Maintains client accounts and stored deposits and withdrawals for dispute lookups.
+///
+/// The stored transactions live in `S`, an in-memory map unless the engine is built
+/// [`with_store`](Self::with_store). Parallel processing, snapshots and consistency
+/// checks walk every stored transaction, so they need the in-memory map.
+///
+/// Cloning snapshots the whole engine for "what-if" exploration. It deep-copies every
+/// account and every stored transaction, so its cost grows with the size of the input seen
+/// so far.
+#[derive(Clone)]
+pub struct PaymentsEngine<S = HashMap<u32, StoredTransaction>> {
+    /// Ordered by client ID, so output can be streamed in sorted order.
+    clients: BTreeMap<u16, ClientAccount>,
+    transactions: S,
+    ledger_hash: [u8; 32],
+    config: Config,
+    /// Number of records passed to `process` so far; the 0-based index of the current record.
+    records_seen: u64,
+    /// Row of the current record in the caller's input, when given through `process_row`.
+    input_row: Option<u64>,
+    /// Transactions currently under dispute.
+    open_disputes: usize,
+    /// Applied transactions in order, when the event log is enabled.
+    events: Option<Vec<LedgerEvent>>,
+    /// Applied chargebacks in order, when the chargeback log is enabled.
+    chargebacks: Option<Vec<ChargebackEvent>>,
+    /// tx IDs of every applied deposit and withdrawal, when tracking is enabled.
+    processed_tx: HashSet<u32>,
+    /// Stored tx IDs that may be evicted, oldest first, when a memory limit is set.
+    stored_order: VecDeque<u32>,
+    /// Stored tx IDs that came up for eviction while under dispute. They are kept out of
+    /// `stored_order` until the dispute closes, so eviction does not rescan them.
+    pinned: HashSet<u32>,
+    /// Stored transactions dropped to stay under the memory limit.
+    evicted: u64,
 }
 
-impl PaymentsEngine {
-    pub fn new() -> Self {
+/// Settings of a [`PaymentsEngine`], as opposed to its state. Build an engine from them
+/// with [`PaymentsEngineBuilder`], or adjust one through its `with_*` setters.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How [`write_output`](PaymentsEngine::write_output) and friends format rows.
+    pub output: OutputOptions,
+    /// Which balances withdrawals may draw from.
+    pub withdrawal_policy: WithdrawalPolicy,
+    /// Whether a full chargeback also reverses what partial resolves already released.
+    pub released_remainder: ReleasedRemainderPolicy,
+    /// Upper bound on any account's total; deposits that would exceed it are rejected.
+    pub max_balance: Option<Decimal>,
+    /// Lower bound on available funds after a withdrawal; withdrawals that would go below
+    /// it are rejected.
+    pub min_balance: Option<Decimal>,
+    /// Upper bound on disputes open at once across all clients; further disputes are rejected.
+    pub max_open_disputes: Option<usize>,
+    /// Upper bound on disputes open at once on a single client; further disputes by that
+    /// client are rejected.
+    pub max_client_disputes: Option<usize>,
+    /// Whether disputing a deposit may hold more than the account has available.
+    pub allow_negative_available: bool,
+    /// Whether every client named by any record gets an account, even if no record of
+    /// theirs was applied.
+    pub include_empty_clients: bool,
+    /// Soft limit, in bytes, on the estimated size of the stored transactions; the oldest
+    /// ones not under dispute are evicted to stay under it.
+    pub max_memory: Option<usize>,
+    /// Whether the tx ID of every applied deposit and withdrawal is remembered, so a
+    /// re-fed one is rejected even after its stored transaction was evicted.
+    pub track_processed_tx: bool,
+    /// Whether deposits and withdrawals must be for whole units; ones with a fractional
+    /// part are rejected.
+    pub integer_amounts: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
         Self {
-            clients: HashMap::new(),
-            transactions: HashMap::new(),
+            output: OutputOptions::default(),
+            withdrawal_policy: WithdrawalPolicy::default(),
+            released_remainder: ReleasedRemainderPolicy::default(),
+            max_balance: None,
+            min_balance: None,
+            max_open_disputes: None,
+            max_client_disputes: None,
+            allow_negative_available: true,
+            include_empty_clients: false,
+            max_memory: None,
+            track_processed_tx: false,
+            integer_amounts: false,
         }
     }
+}
+
+/// Chainable construction of a [`PaymentsEngine`], starting from the default
+/// [`Config`]:
+///
+/// ```
+/// use accounts_manager::PaymentsEngine;
+///
+/// let engine = PaymentsEngine::builder()
+///     .precision(2)
+///     .allow_negative_available(false)
+///     .max_client_disputes(Some(3))
+///     .build()
+///     .unwrap();
+/// assert!(!engine.config().allow_negative_available);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct PaymentsEngineBuilder {
+    config: Config,
+    event_log: bool,
+    chargeback_log: bool,
+}
+
+impl PaymentsEngineBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace every output setting, including any [`precision`](Self::precision) set
+    /// before.
+    pub fn output_options(mut self, options: OutputOptions) -> Self {
+        self.config.output = options;
+        self
+    }
+
+    /// Round output amounts to `precision` decimal places. Checked by
+    /// [`build`](Self::build).
+    pub fn precision(mut self, precision: u32) -> Self {
+        self.config.output.precision = Some(precision);
+        self
+    }
+
+    /// See [`PaymentsEngine::with_withdrawal_policy`].
+    pub fn withdrawal_policy(mut self, policy: WithdrawalPolicy) -> Self {
+        self.config.withdrawal_policy = policy;
+        self
+    }
+
+    /// See [`PaymentsEngine::with_released_remainder`].
+    pub fn released_remainder(mut self, policy: ReleasedRemainderPolicy) -> Self {
+        self.config.released_remainder = policy;
+        self
+    }
+
+    /// See [`PaymentsEngine::with_max_balance`].
+    pub fn max_balance(mut self, cap: Option<Decimal>) -> Self {
+        self.config.max_balance = cap;
+        self
+    }
+
+    /// See [`PaymentsEngine::with_min_balance`].
+    pub fn min_balance(mut self, floor: Option<Decimal>) -> Self {
+        self.config.min_balance = floor;
+        self
+    }
+
+    /// See [`PaymentsEngine::with_max_open_disputes`].
+    pub fn max_open_disputes(mut self, cap: Option<usize>) -> Self {
+        self.config.max_open_disputes = cap;
+        self
+    }
+
+    /// See [`PaymentsEngine::with_max_client_disputes`].
+    pub fn max_client_disputes(mut self, cap: Option<usize>) -> Self {
+        self.config.max_client_disputes = cap;
+        self
+    }
+
+    /// See [`PaymentsEngine::with_negative_available`].
+    pub fn allow_negative_available(mut self, allowed: bool) -> Self {
+        self.config.allow_negative_available = allowed;
+        self
+    }
+
+    /// See [`PaymentsEngine::with_empty_clients`].
+    pub fn include_empty_clients(mut self, include: bool) -> Self {
+        self.config.include_empty_clients = include;
+        self
+    }
+
+    /// See [`PaymentsEngine::with_max_memory`].
+    pub fn max_memory(mut self, limit: Option<usize>) -> Self {
+        self.config.max_memory = limit;
+        self
+    }
+
+    /// See [`PaymentsEngine::with_processed_tx_tracking`].
+    pub fn track_processed_tx(mut self, enabled: bool) -> Self {
+        self.config.track_processed_tx = enabled;
+        self
+    }
+
+    /// See [`PaymentsEngine::with_integer_amounts`].
+    pub fn integer_amounts(mut self, required: bool) -> Self {
+        self.config.integer_amounts = required;
+        self
+    }
+
+    /// See [`PaymentsEngine::with_event_log`].
+    pub fn event_log(mut self, enabled: bool) -> Self {
+        self.event_log = enabled;
+        self
+    }
+
+    /// See [`PaymentsEngine::with_chargeback_log`].
+    pub fn chargeback_log(mut self, enabled: bool) -> Self {
+        self.chargeback_log = enabled;
+        self
+    }
 
-    pub fn process(&mut self, record: TransactionRecord) {
-        match record.r#type {
-            TransactionType::Deposit => self.handle_deposit(record),
-            TransactionType::Withdrawal => self.handle_withdrawal(record),
-            TransactionType::Dispute => self.handle_dispute(record),
-            TransactionType::Resolve => self.handle_resolve(record),
-            TransactionType::Chargeback => self.handle_chargeback(record),
+    /// An engine with the configured settings and no state. Fails if the output
+    /// precision is out of range.
+    pub fn build(mut self) -> Result<PaymentsEngine, EngineError> {
+        if let Some(precision) = self.config.output.precision {
+            self.config.output = self.config.output.with_precision(precision)?;
+        }
+        Ok(PaymentsEngine {
+            config: self.config,
+            ..PaymentsEngine::new()
         }
+        .with_event_log(self.event_log)
+        .with_chargeback_log(self.chargeback_log))
+    }
+}
+
+/// Serializable state of a [`PaymentsEngine`], for checkpointing a long run and resuming
+/// it later with [`PaymentsEngine::from_snapshot`].
+///
+/// Only state is captured. Settings such as the withdrawal policy, caps and output
+/// options are not, so they must be applied again to the restored engine, and the event
+/// log is not carried over.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EngineSnapshot {
+    pub clients: BTreeMap<u16, ClientAccount>,
+    pub transactions: HashMap<u32, StoredTransaction>,
+    pub ledger_hash: [u8; 32],
+    pub records_seen: u64,
+}
+
+/// Aggregate figures of an engine's state, from [`PaymentsEngine::stats`] or
+/// [`EngineSnapshot::summary`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EngineStats {
+    pub accounts: usize,
+    /// Sum of every account's total.
+    pub total: Decimal,
+    pub locked: usize,
+    /// Transactions currently under dispute.
+    pub open_disputes: usize,
+}
+
+impl EngineStats {
+    fn collect(
+        clients: &BTreeMap<u16, ClientAccount>,
+        open_disputes: usize,
+    ) -> Result<Self, EngineError> {
+        Ok(Self {
+            accounts: clients.len(),
+            total: checked_sum(
+                clients.values().map(|account| account.total),
+                "account totals",
+            )?,
+            locked: clients.values().filter(|account| account.locked).count(),
+            open_disputes,
+        })
+    }
+}
+
+/// Sum `values`, failing with [`EngineError::TotalOverflow`] naming `what` instead of
+/// panicking when the sum cannot be represented.
+fn checked_sum(
+    values: impl IntoIterator<Item = Decimal>,
+    what: &'static str,
+) -> Result<Decimal, EngineError> {
+    values
+        .into_iter()
+        .try_fold(Decimal::ZERO, Decimal::checked_add)
+        .ok_or(EngineError::TotalOverflow(what))
+}
+
+/// Applied deposits and withdrawals set against the account totals, from
+/// [`PaymentsEngine::reconciliation`]. `difference` is `total - net`: zero unless
+/// chargebacks reversed funds or opening balances were seeded.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Reconciliation {
+    /// Sum of every applied deposit.
+    pub deposits: Decimal,
+    /// Sum of every applied withdrawal.
+    pub withdrawals: Decimal,
+    /// `deposits - withdrawals`.
+    pub net: Decimal,
+    /// Sum of every account's total.
+    pub total: Decimal,
+    pub difference: Decimal,
+}
+
+impl EngineSnapshot {
+    /// Count accounts, locked accounts and open disputes and sum the account totals,
+    /// without formatting any account. Fails if the sum overflows.
+    pub fn summary(&self) -> Result<EngineStats, EngineError> {
+        let open_disputes = self
+            .transactions
+            .values()
+            .filter(|stored| stored.under_dispute)
+            .count();
+        EngineStats::collect(&self.clients, open_disputes)
+    }
+}
+
+impl Default for PaymentsEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PaymentsEngine {
+    pub fn new() -> Self {
+        Self::with_store(HashMap::new())
+    }
+
+    /// Start configuring an engine; see [`PaymentsEngineBuilder`].
+    pub fn builder() -> PaymentsEngineBuilder {
+        PaymentsEngineBuilder::new()
     }
 
-    fn handle_deposit(&mut self, record: TransactionRecord) {
-        if let Some(amount) = record.amount {
-            let account = self
+    /// Process `records` on `threads` worker threads, each owning the accounts and stored
+    /// transactions of the clients with `client % threads` equal to its index. Records are
+    /// routed by their `client` field, so disputes, resolves and chargebacks reach the
+    /// shard that holds the referenced transaction. Returns the input index and reason of
+    /// every rejected record, in input order.
+    ///
+    /// Shards are merged back into the client map, which is ordered by client ID, so
+    /// the order in which workers finish never shows in the output: it is byte-for-byte
+    /// what sequential processing writes, and safe to compare against golden files.
+    ///
+    /// Balances and lock indices match sequential processing, with some caveats:
+    /// - tx IDs are only checked for reuse within a shard, so inputs must keep them
+    ///   unique across clients.
+    /// - A dispute naming the wrong client is rejected as
+    ///   [`UnknownTransaction`](TransactionRejection::UnknownTransaction) rather than
+    ///   [`ClientMismatch`](TransactionRejection::ClientMismatch).
+    /// - The ledger hash and event log depend on a global order, so they are not
+    ///   updated. The chargeback log is, since each entry carries its input index.
+    /// - The [open dispute cap](Self::with_max_open_disputes) is enforced per shard, so
+    ///   the shards together may hold more disputes open than the cap. So is the
+    ///   [memory limit](Self::with_max_memory).
+    pub fn process_parallel<I>(
+        &mut self,
+        records: I,
+        threads: NonZeroUsize,
+    ) -> Vec<(usize, TransactionRejection)>
+    where
+        I: IntoIterator<Item = TransactionRecord>,
+    {
+        let shard_count = threads.get();
+        let mut shards: Vec<PaymentsEngine> =
+            (0..shard_count).map(|_| self.empty_shard()).collect();
+        for (client, account) in std::mem::take(&mut self.clients) {
+            shards[usize::from(client) % shard_count]
                 .clients
-                .entry(record.client)
-                .or_insert_with(ClientAccount::new);
+                .insert(client, account);
+        }
+        for (tx, stored) in self.transactions.drain() {
+            let shard = &mut shards[usize::from(stored.client) % shard_count];
+            shard.open_disputes += usize::from(stored.under_dispute);
+            shard.transactions.insert(tx, stored);
+        }
+        self.open_disputes = 0;
+
+        let first_index = self.records_seen;
+        let (processed, mut rejections) = std::thread::scope(|scope| {
+            let mut senders = Vec::with_capacity(shard_count);
+            let mut workers = Vec::with_capacity(shard_count);
+            for mut shard in shards {
+                let (sender, receiver) =
+                    mpsc::sync_channel::<(usize, TransactionRecord)>(PARALLEL_CHANNEL_CAPACITY);
+                senders.push(sender);
+                workers.push(scope.spawn(move || {
+                    let mut rejections = Vec::new();
+                    for (idx, record) in receiver {
+                        // Keep lock indices global rather than per shard
+                        shard.records_seen = first_index + idx as u64;
+                        if let Err(rejection) = shard.process(record) {
+                            rejections.push((idx, rejection));
+                        }
+                    }
+                    (shard, rejections)
+                }));
+            }
 
-            if account.locked {
-                return;
+            let mut processed = 0;
+            for (idx, record) in records.into_iter().enumerate() {
+                let shard = usize::from(record.client) % shard_count;
+                senders[shard]
+                    .send((idx, record))
+                    .expect("shard workers outlive the router");
+                processed += 1;
             }
+            drop(senders);
 
-            account.deposit(amount);
+            let mut rejections = Vec::new();
+            for worker in workers {
+                let (shard, shard_rejections) = worker.join().expect("shard worker panicked");
+                self.clients.extend(shard.clients);
+                self.transactions.extend(shard.transactions);
+                self.open_disputes += shard.open_disputes;
+                self.processed_tx.extend(shard.processed_tx);
+                self.stored_order.extend(shard.stored_order);
+                self.pinned.extend(shard.pinned);
+                self.evicted += shard.evicted;
+                if let (Some(log), Some(shard_log)) = (&mut self.chargebacks, shard.chargebacks) {
+                    log.extend(shard_log);
+                }
+                rejections.extend(shard_rejections);
+            }
+            (processed, rejections)
+        });
 
-            // Store deposit metadata for future dispute lookups
-            self.transactions.insert(
-                record.tx,
-                StoredTransaction {
-                    client: record.client,
-                    amount,
-                    under_dispute: false,
-                },
-            );
+        self.records_seen = first_index + processed as u64;
+        if let Some(log) = &mut self.chargebacks {
+            log.sort_by_key(|event| event.sequence_index);
         }
+        rejections.sort_unstable_by_key(|&(idx, _)| idx);
+        rejections
     }
 
-    fn handle_withdrawal(&mut self, record: TransactionRecord) {
-        if let Some(amount) = record.amount {
-            let account = self
-                .clients
-                .entry(record.client)
-                .or_insert_with(ClientAccount::new);
+    /// An engine with this one's settings and no state, for [`process_parallel`](Self::process_parallel).
+    fn empty_shard(&self) -> PaymentsEngine {
+        PaymentsEngine {
+            config: self.config.clone(),
+            chargebacks: self.chargebacks.as_ref().map(|_| Vec::new()),
+            ..PaymentsEngine::new()
+        }
+    }
 
-            if account.locked {
-                return;
-            }
+    /// Capture the accounts and stored transactions processed so far, together with the
+    /// ledger hash and record count, so processing can resume from this point.
+    pub fn to_snapshot(&self) -> EngineSnapshot {
+        EngineSnapshot {
+            clients: self.clients.clone(),
+            transactions: self.transactions.clone(),
+            ledger_hash: self.ledger_hash,
+            records_seen: self.records_seen,
+        }
+    }
 
-            account.withdraw(amount);
+    /// An engine with default settings resuming from `snapshot`. Chain the `with_*`
+    /// setters to restore the settings of the original run.
+    pub fn from_snapshot(snapshot: EngineSnapshot) -> Self {
+        let open_disputes = snapshot
+            .transactions
+            .values()
+            .filter(|stored| stored.under_dispute)
+            .count();
+        Self {
+            clients: snapshot.clients,
+            transactions: snapshot.transactions,
+            ledger_hash: snapshot.ledger_hash,
+            records_seen: snapshot.records_seen,
+            open_disputes,
+            ..Self::new()
         }
     }
 
-    fn handle_dispute(&mut self, record: TransactionRecord) {
-        if let Some(stored) = self.transactions.get_mut(&record.tx) {
-            if stored.client != record.client {
-                return;
-            }
+    /// Cross-check the stored transactions against the client accounts: every stored
+    /// transaction must belong to a known client, every transaction under dispute must
+    /// have a hold on that client of at most its amount, and no client's per-tx holds
+    /// may sum to more than its `held` balance. No account may have more available than
+    /// its total, which would mean a negative held balance, e.g. from a hold or release
+    /// applied with the wrong sign.
+    pub fn verify_consistency(&self) -> Result<(), String> {
+        for (tx, stored) in &self.transactions {
+            let Some(account) = self.clients.get(&stored.client) else {
+                return Err(format!(
+                    "tx {tx} belongs to unknown client {}",
+                    stored.client
+                ));
+            };
 
-            // Prevent double-disputes would incorrectly drain available into held
-            if stored.under_dispute {
-                return;
+            if stored.under_dispute
+                && account
+                    .holds
+                    .get(tx)
+                    .is_none_or(|&held| held > stored.amount)
+            {
+                return Err(format!(
+                    "tx {tx} is under dispute but client {} has no hold of at most {}",
+                    stored.client, stored.amount
+                ));
             }
+        }
 
-            if let Some(account) = self.clients.get_mut(&record.client) {
-                if account.locked {
-                    return;
-                }
+        for (client, account) in &self.clients {
+            if account.available > account.total {
+                return Err(format!(
+                    "client {client} has {} available but a total of only {}",
+                    account.available, account.total
+                ));
+            }
 
-                stored.under_dispute = true;
-                account.hold(stored.amount);
+            let held: Decimal = account.holds.values().sum();
+            if held > account.held {
+                return Err(format!(
+                    "client {client} holds {held} across disputes but held is {}",
+                    account.held
+                ));
             }
         }
+
+        Ok(())
+    }
+}
+
+impl<S: TxStore> PaymentsEngine<S> {
+    /// An engine keeping its stored deposits and withdrawals in `store` instead of the
+    /// default in-memory map.
+    pub fn with_store(store: S) -> Self {
+        Self {
+            clients: BTreeMap::new(),
+            transactions: store,
+            ledger_hash: [0; 32],
+            config: Config::default(),
+            records_seen: 0,
+            input_row: None,
+            open_disputes: 0,
+            events: None,
+            chargebacks: None,
+            processed_tx: HashSet::new(),
+            stored_order: VecDeque::new(),
+            pinned: HashSet::new(),
+            evicted: 0,
+        }
     }
 
-    fn handle_resolve(&mut self, record: TransactionRecord) {
-        if let Some(stored) = self.transactions.get_mut(&record.tx) {
-            if stored.client != record.client {
-                return;
-            }
+    /// The store holding this engine's deposits and withdrawals.
+    pub fn store(&self) -> &S {
+        &self.transactions
+    }
 
-            // Can only resolve a transaction that is currently under dispute
-            if !stored.under_dispute {
-                return;
-            }
+    /// The settings this engine runs with.
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Choose which balances withdrawals may draw from. Defaults to
+    /// [`WithdrawalPolicy::AvailableOnly`].
+    pub fn with_withdrawal_policy(mut self, policy: WithdrawalPolicy) -> Self {
+        self.config.withdrawal_policy = policy;
+        self
+    }
+
+    /// Choose whether a chargeback without an amount also reverses the part of the
+    /// transaction that partial resolves released before. Defaults to
+    /// [`ReleasedRemainderPolicy::Keep`], which charges back only what is still held.
+    pub fn with_released_remainder(mut self, policy: ReleasedRemainderPolicy) -> Self {
+        self.config.released_remainder = policy;
+        self
+    }
+
+    /// Reject deposits that would push an account's total above `cap`. `None`, the
+    /// default, leaves balances unlimited.
+    pub fn with_max_balance(mut self, cap: Option<Decimal>) -> Self {
+        self.config.max_balance = cap;
+        self
+    }
+
+    /// Reject withdrawals that would leave available funds below `floor`, even when the
+    /// account could cover them. `None`, the default, only requires the funds to be there.
+    pub fn with_min_balance(mut self, floor: Option<Decimal>) -> Self {
+        self.config.min_balance = floor;
+        self
+    }
+
+    /// Reject disputes while `cap` transactions are already under dispute across all
+    /// clients. A dispute stays open until it is fully resolved or charged back. `None`,
+    /// the default, leaves the number unlimited.
+    pub fn with_max_open_disputes(mut self, cap: Option<usize>) -> Self {
+        self.config.max_open_disputes = cap;
+        self
+    }
+
+    /// Reject a client's disputes while `cap` of its transactions are already under
+    /// dispute. `None`, the default, leaves the number unlimited.
+    pub fn with_max_client_disputes(mut self, cap: Option<usize>) -> Self {
+        self.config.max_client_disputes = cap;
+        self
+    }
+
+    /// Whether a dispute may hold a deposit whose funds were partly withdrawn since,
+    /// driving available negative. On by default, as the spec allows it; when off, such
+    /// disputes are rejected with
+    /// [`DisputeExceedsAvailable`](TransactionRejection::DisputeExceedsAvailable).
+    pub fn with_negative_available(mut self, allowed: bool) -> Self {
+        self.config.allow_negative_available = allowed;
+        self
+    }
+
+    /// Give every client named by any record an account, so clients whose records were
+    /// all rejected, e.g. a lone dispute of an unknown transaction, are written with zero
+    /// balances. Off by default: such clients do not appear in the output.
+    pub fn with_empty_clients(mut self, include: bool) -> Self {
+        self.config.include_empty_clients = include;
+        self
+    }
+
+    /// Record every applied transaction in an event log, readable through
+    /// [`events`](Self::events). Off by default, since the log grows with the input.
+    pub fn with_event_log(mut self, enabled: bool) -> Self {
+        self.events = enabled.then(Vec::new);
+        self
+    }
+
+    /// Keep the estimated size of the stored transactions under `limit` bytes by evicting
+    /// the oldest ones not under dispute once it is exceeded. An evicted transaction can no
+    /// longer be disputed, and its tx ID is no longer caught as a duplicate. Disputed
+    /// transactions are never evicted, so the limit is soft. Only transactions stored
+    /// after the limit is set are tracked for eviction.
+    pub fn with_max_memory(mut self, limit: Option<usize>) -> Self {
+        self.config.max_memory = limit;
+        self
+    }
+
+    /// Remember the tx ID of every applied deposit and withdrawal, apart from the stored
+    /// transactions, and reject any later deposit or withdrawal reusing one as a
+    /// [`DuplicateTransaction`](TransactionRejection::DuplicateTransaction). The stored
+    /// transactions already catch reuse unless a [memory limit](Self::with_max_memory)
+    /// evicted them, so this guards re-fed input at a cost of one `u32` per transaction.
+    pub fn with_processed_tx_tracking(mut self, enabled: bool) -> Self {
+        self.config.track_processed_tx = enabled;
+        self
+    }
+
+    /// Reject deposits and withdrawals whose amount has a fractional part (`5.50`) as
+    /// [`FractionalAmount`](TransactionRejection::FractionalAmount), for deployments that
+    /// only deal in whole units. `5.0` is a whole amount.
+    pub fn with_integer_amounts(mut self, required: bool) -> Self {
+        self.config.integer_amounts = required;
+        self
+    }
+
+    /// Record every applied chargeback in a log, readable through
+    /// [`chargeback_log`](Self::chargeback_log), so auditors can see which tx locked an
+    /// account and where it fell in the input. Off by default.
+    pub fn with_chargeback_log(mut self, enabled: bool) -> Self {
+        self.chargebacks = enabled.then(Vec::new);
+        self
+    }
+
+    /// Replace the settings used by [`write_output`](Self::write_output).
+    pub fn with_output_options(mut self, options: OutputOptions) -> Self {
+        self.config.output = options;
+        self
+    }
+
+    /// Like [`process`](Self::process), for a record read from row `row` (0-based) of
+    /// the caller's input. The row, rather than the count of processed records, becomes
+    /// the `sequence_index` of a chargeback it applies, so it still points at the input
+    /// when rows before it were malformed or filtered out.
+    pub fn process_row(
+        &mut self,
+        record: TransactionRecord,
+        row: u64,
+    ) -> Result<(), TransactionRejection> {
+        self.input_row = Some(row);
+        let result = self.process(record);
+        self.input_row = None;
+        result
+    }
+
+    pub fn process(&mut self, record: TransactionRecord) -> Result<(), TransactionRejection> {
+        if self.config.include_empty_clients {
+            self.clients.entry(record.client).or_default();
+        }
+        let result = match record.r#type {
+            TransactionType::Deposit => self.handle_deposit(&record),
+            TransactionType::Withdrawal => self.handle_withdrawal(&record),
+            TransactionType::Dispute => self.handle_dispute(&record),
+            TransactionType::Resolve => self.handle_resolve(&record),
+            TransactionType::Chargeback => self.handle_chargeback(&record),
+        };
 
+        #[cfg(feature = "tracing")]
+        if let Err(rejection) = &result {
+            trace_rejection(&record, rejection);
+        }
+        if result.is_ok() {
             if let Some(account) = self.clients.get_mut(&record.client) {
-                if account.locked {
-                    return;
-                }
+                account.tx_count += 1;
+            }
+            self.record_applied(&record);
+        }
+        self.records_seen += 1;
+        result
+    }
+
+    /// Apply every record in order, returning the index and reason for each one
+    /// that was rejected.
+    ///
+    /// Runs of consecutive deposits for the same client are credited with a single
+    /// account lookup; the results are identical to calling [`process`](Self::process)
+    /// on each record.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "debug", skip_all, fields(records = records.len()))
+    )]
+    pub fn process_batch(
+        &mut self,
+        records: &[TransactionRecord],
+    ) -> Vec<(usize, TransactionRejection)> {
+        let mut rejections = Vec::new();
+        let mut start = 0;
+
+        while start < records.len() {
+            let first = &records[start];
+            let len = records[start..]
+                .iter()
+                .position(|r| r.r#type != TransactionType::Deposit || r.client != first.client)
+                .unwrap_or(records.len() - start);
 
-                stored.under_dispute = false;
-                account.release(stored.amount);
+            // Lone records, deposits included, are not worth the run bookkeeping
+            if len <= 1 {
+                if let Err(rejection) = self.process(first.clone()) {
+                    rejections.push((start, rejection));
+                }
+                start += 1;
+                continue;
+            }
+            for (offset, result) in self
+                .process_deposit_run(&records[start..start + len])
+                .into_iter()
+                .enumerate()
+            {
+                if let Err(rejection) = result {
+                    rejections.push((start + offset, rejection));
+                }
             }
+            start += len;
         }
+
+        rejections
     }
 
-    fn handle_chargeback(&mut self, record: TransactionRecord) {
-        if let Some(stored) = self.transactions.get_mut(&record.tx) {
-            if stored.client != record.client {
-                return;
+    /// Apply consecutive deposits for one client, validating and crediting each one as
+    /// [`handle_deposit`](Self::handle_deposit) would, but looking the account up once.
+    fn process_deposit_run(
+        &mut self,
+        run: &[TransactionRecord],
+    ) -> Vec<Result<(), TransactionRejection>> {
+        let client = run[0].client;
+        let existing = self.clients.remove(&client);
+        let existed = existing.is_some();
+        let mut account = existing.unwrap_or_default();
+        let mut any_applied = false;
+
+        let mut results = Vec::with_capacity(run.len());
+        for record in run {
+            let result = self.apply_run_deposit(&mut account, record);
+            #[cfg(feature = "tracing")]
+            if let Err(rejection) = &result {
+                trace_rejection(record, rejection);
+            }
+            if result.is_ok() {
+                any_applied = true;
+                self.record_applied(record);
             }
+            results.push(result);
+        }
+
+        if existed || any_applied || self.config.include_empty_clients {
+            self.clients.insert(client, account);
+        }
+        self.records_seen += run.len() as u64;
+        results
+    }
+
+    /// One deposit of a run, against an account temporarily taken out of `clients`.
+    /// Traced under the same span as [`handle_deposit`](Self::handle_deposit), so
+    /// coalesced deposits look the same as lone ones.
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(
+            name = "handle_deposit",
+            level = "trace",
+            skip_all,
+            fields(client = record.client, tx = record.tx)
+        )
+    )]
+    fn apply_run_deposit(
+        &mut self,
+        account: &mut ClientAccount,
+        record: &TransactionRecord,
+    ) -> Result<(), TransactionRejection> {
+        let Some(amount) = record.amount else {
+            return Err(TransactionRejection::MissingAmount);
+        };
+        if amount <= Decimal::ZERO {
+            return Err(TransactionRejection::NonPositiveAmount);
+        }
+        if self.config.integer_amounts && !amount.fract().is_zero() {
+            return Err(TransactionRejection::FractionalAmount);
+        }
+        if self.already_applied(record.tx) {
+            return Err(TransactionRejection::DuplicateTransaction);
+        }
+        if self.exceeds_max_balance(account.total, amount) {
+            return Err(TransactionRejection::BalanceCapExceeded);
+        }
+        if account.locked {
+            return Err(TransactionRejection::AccountLocked);
+        }
 
-            // Can only chargeback a transaction that is currently under dispute
-            if !stored.under_dispute {
-                return;
+        account.deposit(amount)?;
+        account.tx_count += 1;
+        self.store_transaction(
+            record.tx,
+            StoredTransaction {
+                client: record.client,
+                amount,
+                kind: StoredKind::Deposit,
+                under_dispute: false,
+            },
+        );
+        Ok(())
+    }
+
+    /// Store a deposit or withdrawal for later dispute lookups, then evict old ones if
+    /// that takes the store over the memory limit.
+    fn store_transaction(&mut self, tx: u32, stored: StoredTransaction) {
+        self.transactions.insert(tx, stored);
+        if self.config.track_processed_tx {
+            self.processed_tx.insert(tx);
+        }
+        let Some(limit) = self.config.max_memory else {
+            return;
+        };
+        self.stored_order.push_back(tx);
+
+        let max_entries = limit / STORED_ENTRY_BYTES;
+        while self.stored_order.len() + self.pinned.len() > max_entries {
+            let Some(oldest) = self.stored_order.pop_front() else {
+                break;
+            };
+            if self
+                .transactions
+                .get(oldest)
+                .is_some_and(|stored| stored.under_dispute)
+            {
+                self.pinned.insert(oldest);
+            } else {
+                self.transactions.remove(oldest);
+                self.evicted += 1;
             }
+        }
+    }
 
-            if let Some(account) = self.clients.get_mut(&record.client) {
-                if account.locked {
-                    return;
-                }
+    /// Whether a deposit or withdrawal with this tx ID was already applied.
+    fn already_applied(&self, tx: u32) -> bool {
+        self.transactions.contains(tx) || self.processed_tx.contains(&tx)
+    }
+
+    /// Number of stored transactions evicted to stay under the
+    /// [memory limit](Self::with_max_memory).
+    pub fn evicted(&self) -> u64 {
+        self.evicted
+    }
+
+    /// Whether depositing `amount` onto an account holding `total` would break the
+    /// `--max-balance` cap. A total too large to represent always does.
+    fn exceeds_max_balance(&self, total: Decimal, amount: Decimal) -> bool {
+        self.config
+            .max_balance
+            .is_some_and(|cap| total.checked_add(amount).is_none_or(|total| total > cap))
+    }
+
+    /// Start `opening.client` from the given balances, replacing any existing state for
+    /// that account. Meant to be called before any records are processed. Balances
+    /// whose total overflows are rejected and leave the account untouched.
+    pub fn seed_account(&mut self, opening: &OpeningBalance) -> Result<(), TransactionRejection> {
+        self.clients
+            .insert(opening.client, ClientAccount::opening(opening)?);
+        Ok(())
+    }
+
+    /// Apply an externally decided dispute outcome as a resolve or chargeback on behalf
+    /// of the client that owns `resolution.tx`. The usual rules still hold: unknown
+    /// transactions and ones not currently under dispute are rejected.
+    pub fn apply_resolution(
+        &mut self,
+        resolution: ResolutionRecord,
+    ) -> Result<(), TransactionRejection> {
+        let Some(stored) = self.transactions.get(resolution.tx) else {
+            return Err(TransactionRejection::UnknownTransaction);
+        };
+
+        let r#type = match resolution.outcome {
+            ResolutionOutcome::Resolve => TransactionType::Resolve,
+            ResolutionOutcome::Chargeback => TransactionType::Chargeback,
+        };
+        self.process(TransactionRecord {
+            r#type,
+            client: stored.client,
+            tx: resolution.tx,
+            amount: None,
+        })
+    }
+
+    /// Cumulative SHA-256 digest over every applied transaction, in application order.
+    ///
+    /// Two engines that applied the same transactions in the same order report the
+    /// same hash; rejected records do not contribute.
+    pub fn ledger_hash(&self) -> [u8; 32] {
+        self.ledger_hash
+    }
+
+    /// Number of records passed to [`process`](Self::process), including rejected ones.
+    pub fn records_processed(&self) -> u64 {
+        self.records_seen
+    }
 
-                stored.under_dispute = false;
-                account.chargeback(stored.amount);
+    /// Account count, locked account count, open disputes and the sum of all account
+    /// totals, for a quick sanity check of a run. Fails if the sum overflows.
+    pub fn stats(&self) -> Result<EngineStats, EngineError> {
+        EngineStats::collect(&self.clients, self.open_disputes)
+    }
+
+    /// Compare the net of all applied deposits and withdrawals with the sum of the
+    /// account totals, for an end-to-end check that every movement of funds is accounted
+    /// for. Fails if any of the sums cannot be represented.
+    pub fn reconciliation(&self) -> Result<Reconciliation, EngineError> {
+        let deposits = checked_sum(
+            self.clients.values().map(|account| account.gross_in),
+            "deposits",
+        )?;
+        let withdrawals = checked_sum(
+            self.clients.values().map(|account| account.gross_out),
+            "withdrawals",
+        )?;
+        let net = deposits
+            .checked_sub(withdrawals)
+            .ok_or(EngineError::TotalOverflow("deposits less withdrawals"))?;
+        let total = checked_sum(
+            self.clients.values().map(|account| account.total),
+            "account totals",
+        )?;
+        let difference = total
+            .checked_sub(net)
+            .ok_or(EngineError::TotalOverflow("account totals less net flows"))?;
+        Ok(Reconciliation {
+            deposits,
+            withdrawals,
+            net,
+            total,
+            difference,
+        })
+    }
+
+    /// Fold an applied record into the ledger hash and, if enabled, the event log.
+    fn record_applied(&mut self, record: &TransactionRecord) {
+        self.fold_into_ledger_hash(record);
+        if let Some(events) = &mut self.events {
+            events.push(LedgerEvent::from_applied(record));
+        }
+    }
+
+    fn fold_into_ledger_hash(&mut self, record: &TransactionRecord) {
+        let mut hasher = Sha256::new();
+        hasher.update(self.ledger_hash);
+        hasher.update(record.r#type.as_str());
+        hasher.update(record.client.to_be_bytes());
+        hasher.update(record.tx.to_be_bytes());
+        match record.amount {
+            Some(amount) => {
+                hasher.update([1]);
+                hasher.update(amount.serialize());
             }
+            None => hasher.update([0]),
         }
+        self.ledger_hash = hasher.finalize().into();
     }
 
-    pub fn write_output<W: io::Write>(&self, writer: W) -> Result<(), EngineError> {
-        let mut wtr = csv::Writer::from_writer(writer);
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(client = record.client, tx = record.tx))
+    )]
+    fn handle_deposit(&mut self, record: &TransactionRecord) -> Result<(), TransactionRejection> {
+        let Some(amount) = record.amount else {
+            return Err(TransactionRejection::MissingAmount);
+        };
 
-        for (&client_id, account) in &self.clients {
-            wtr.serialize(OutputRecord {
-                client: client_id,
-                available: account.available,
-                held: account.held,
-                total: account.total,
-                locked: account.locked,
-            })?;
+        // A negative deposit would act as a withdrawal that skips the balance check
+        if amount <= Decimal::ZERO {
+            return Err(TransactionRejection::NonPositiveAmount);
         }
 
-        wtr.flush()?;
+        if self.config.integer_amounts && !amount.fract().is_zero() {
+            return Err(TransactionRejection::FractionalAmount);
+        }
+
+        // tx IDs are globally unique; overwriting would point later disputes at the wrong amount
+        if self.already_applied(record.tx) {
+            return Err(TransactionRejection::DuplicateTransaction);
+        }
+
+        let total = self
+            .clients
+            .get(&record.client)
+            .map_or(Decimal::ZERO, |account| account.total);
+        if self.exceeds_max_balance(total, amount) {
+            return Err(TransactionRejection::BalanceCapExceeded);
+        }
+
+        let account = self.clients.entry(record.client).or_default();
+
+        if account.locked {
+            return Err(TransactionRejection::AccountLocked);
+        }
+
+        account.deposit(amount)?;
+
+        // Store deposit metadata for future dispute lookups
+        self.store_transaction(
+            record.tx,
+            StoredTransaction {
+                client: record.client,
+                amount,
+                kind: StoredKind::Deposit,
+                under_dispute: false,
+            },
+        );
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(client = record.client, tx = record.tx))
+    )]
+    fn handle_withdrawal(
+        &mut self,
+        record: &TransactionRecord,
+    ) -> Result<(), TransactionRejection> {
+        let Some(amount) = record.amount else {
+            return Err(TransactionRejection::MissingAmount);
+        };
+
+        if amount <= Decimal::ZERO {
+            return Err(TransactionRejection::NonPositiveAmount);
+        }
+
+        if self.config.integer_amounts && !amount.fract().is_zero() {
+            return Err(TransactionRejection::FractionalAmount);
+        }
+
+        if self.already_applied(record.tx) {
+            return Err(TransactionRejection::DuplicateTransaction);
+        }
+
+        let account = self.clients.entry(record.client).or_default();
+
+        if account.locked {
+            return Err(TransactionRejection::AccountLocked);
+        }
+
+        if self.config.min_balance.is_some_and(|floor| {
+            account
+                .available
+                .checked_sub(amount)
+                .is_none_or(|left| left < floor)
+        }) {
+            return Err(TransactionRejection::BelowMinimumBalance);
+        }
+
+        let drawable = match self.config.withdrawal_policy {
+            WithdrawalPolicy::AvailableOnly => Vec::new(),
+            WithdrawalPolicy::AvailableThenHeld => disputed_deposits(account, &self.transactions),
+        };
+        account.withdraw(amount, self.config.withdrawal_policy, &drawable)?;
+
+        // Store withdrawal metadata too, so it can be disputed
+        self.store_transaction(
+            record.tx,
+            StoredTransaction {
+                client: record.client,
+                amount,
+                kind: StoredKind::Withdrawal,
+                under_dispute: false,
+            },
+        );
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(client = record.client, tx = record.tx))
+    )]
+    fn handle_dispute(&mut self, record: &TransactionRecord) -> Result<(), TransactionRejection> {
+        let Some(stored) = self.transactions.get(record.tx) else {
+            return Err(TransactionRejection::UnknownTransaction);
+        };
+
+        if stored.client != record.client {
+            return Err(TransactionRejection::ClientMismatch);
+        }
+
+        // Prevent double-disputes would incorrectly drain available into held
+        if stored.under_dispute {
+            return Err(TransactionRejection::AlreadyDisputed);
+        }
+
+        // Deposits and withdrawals are only stored with positive amounts; anything
+        // else is corrupt and would hold a negative amount
+        if stored.amount <= Decimal::ZERO {
+            return Err(TransactionRejection::NonPositiveStoredAmount);
+        }
+
+        // A stored transaction without an account signals an internal inconsistency
+        let Some(account) = self.clients.get_mut(&record.client) else {
+            return Err(TransactionRejection::AccountNotFound);
+        };
+
+        if account.locked {
+            return Err(TransactionRejection::AccountLocked);
+        }
+
+        if !self.config.allow_negative_available
+            && stored.kind == StoredKind::Deposit
+            && account.available < stored.amount
+        {
+            return Err(TransactionRejection::DisputeExceedsAvailable);
+        }
+
+        if self
+            .config
+            .max_open_disputes
+            .is_some_and(|cap| self.open_disputes >= cap)
+        {
+            return Err(TransactionRejection::DisputeLimitReached);
+        }
+
+        // Each open dispute keeps a hold until it is fully resolved or charged back
+        if self
+            .config
+            .max_client_disputes
+            .is_some_and(|cap| account.holds.len() >= cap)
+        {
+            return Err(TransactionRejection::ClientDisputeLimitReached);
+        }
+
+        account.hold(record.tx, stored.amount, stored.kind)?;
+        self.transactions.set_disputed(record.tx, true);
+        self.open_disputes += 1;
+        Ok(())
+    }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(client = record.client, tx = record.tx))
+    )]
+    fn handle_resolve(&mut self, record: &TransactionRecord) -> Result<(), TransactionRejection> {
+        let Some(stored) = self.transactions.get(record.tx) else {
+            return Err(TransactionRejection::UnknownTransaction);
+        };
+
+        if stored.client != record.client {
+            return Err(TransactionRejection::ClientMismatch);
+        }
+
+        // Can only resolve a transaction that is currently under dispute
+        if !stored.under_dispute {
+            return Err(TransactionRejection::NotDisputed);
+        }
+
+        // A stored transaction without an account signals an internal inconsistency
+        let Some(account) = self.clients.get_mut(&record.client) else {
+            return Err(TransactionRejection::AccountNotFound);
+        };
+
+        if account.locked {
+            return Err(TransactionRejection::AccountLocked);
+        }
+
+        // An inconsistent state must not drive held negative
+        if !account.holds_enough_for(record.tx) {
+            return Err(TransactionRejection::InsufficientHeld);
+        }
+
+        let amount = settled_amount(record, account)?;
+        account.release(record.tx, stored.kind, amount)?;
+        if account.holds.contains_key(&record.tx) {
+            *account.released.entry(record.tx).or_default() += amount;
+        } else {
+            self.transactions.set_disputed(record.tx, false);
+            self.open_disputes = self.open_disputes.saturating_sub(1);
+            unpin(record.tx, &mut self.pinned, &mut self.stored_order);
+        }
         Ok(())
     }
+
+    #[cfg_attr(
+        feature = "tracing",
+        tracing::instrument(level = "trace", skip_all, fields(client = record.client, tx = record.tx))
+    )]
+    fn handle_chargeback(
+        &mut self,
+        record: &TransactionRecord,
+    ) -> Result<(), TransactionRejection> {
+        let Some(stored) = self.transactions.get(record.tx) else {
+            return Err(TransactionRejection::UnknownTransaction);
+        };
+
+        if stored.client != record.client {
+            return Err(TransactionRejection::ClientMismatch);
+        }
+
+        // Can only chargeback a transaction that is currently under dispute
+        if !stored.under_dispute {
+            return Err(TransactionRejection::NotDisputed);
+        }
+
+        // A stored transaction without an account signals an internal inconsistency
+        let Some(account) = self.clients.get_mut(&record.client) else {
+            return Err(TransactionRejection::AccountNotFound);
+        };
+
+        if account.locked {
+            return Err(TransactionRejection::AccountLocked);
+        }
+
+        // An inconsistent state must not drive held negative
+        if !account.holds_enough_for(record.tx) {
+            return Err(TransactionRejection::InsufficientHeld);
+        }
+
+        let amount = settled_amount(record, account)?;
+        // Only a full chargeback reverses the whole transaction; a partial one names
+        // its amount
+        let reclaimed = match (self.config.released_remainder, record.amount) {
+            (ReleasedRemainderPolicy::Reverse, None) => account
+                .released
+                .get(&record.tx)
+                .copied()
+                .unwrap_or_default(),
+            _ => Decimal::ZERO,
+        };
+        account.chargeback(record.tx, stored.kind, amount, reclaimed)?;
+        self.transactions.set_disputed(record.tx, false);
+        self.open_disputes = self.open_disputes.saturating_sub(1);
+        unpin(record.tx, &mut self.pinned, &mut self.stored_order);
+        account.locked_at = Some(self.records_seen);
+        if let Some(chargebacks) = &mut self.chargebacks {
+            chargebacks.push(ChargebackEvent {
+                client: record.client,
+                tx: record.tx,
+                amount: amount + reclaimed,
+                sequence_index: self.input_row.unwrap_or(self.records_seen),
+            });
+        }
+
+        let closed = resolve_pending_disputes(account, &mut self.transactions)?;
+        self.open_disputes = self.open_disputes.saturating_sub(closed.len());
+        for tx in closed {
+            unpin(tx, &mut self.pinned, &mut self.stored_order);
+        }
+        Ok(())
+    }
+
+    /// Transactions applied so far, in order. Empty unless the engine was built
+    /// [`with_event_log`](Self::with_event_log). Balances seeded through
+    /// [`seed_account`](Self::seed_account) are not events.
+    pub fn events(&self) -> &[LedgerEvent] {
+        self.events.as_deref().unwrap_or_default()
+    }
+
+    /// Write the event log as `type,client,tx,amount` CSV, which the engine can read
+    /// back as input.
+    pub fn write_events<W: io::Write>(&self, writer: W) -> Result<(), EngineError> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        for event in self.events() {
+            wtr.serialize(event)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Chargebacks applied so far, in input order. Empty unless the engine was built
+    /// [`with_chargeback_log`](Self::with_chargeback_log).
+    pub fn chargeback_log(&self) -> &[ChargebackEvent] {
+        self.chargebacks.as_deref().unwrap_or_default()
+    }
+
+    /// Write the chargeback log as `client,tx,amount,sequence_index` CSV.
+    pub fn write_chargeback_log<W: io::Write>(&self, writer: W) -> Result<(), EngineError> {
+        let mut wtr = csv::Writer::from_writer(writer);
+        for event in self.chargeback_log() {
+            wtr.serialize(event)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Write `client,locked_at` for every locked account, sorted by client, where
+    /// `locked_at` is the 0-based index of the chargeback among processed records.
+    pub fn write_lock_log<W: io::Write>(&self, writer: W) -> Result<(), EngineError> {
+        let locks = self.clients.iter().filter_map(|(&client, account)| {
+            account
+                .locked_at
+                .map(|locked_at| LockRecord { client, locked_at })
+        });
+
+        let mut wtr = csv::Writer::from_writer(writer);
+        for lock in locks {
+            wtr.serialize(lock)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// Write `client,gross_in,gross_out,net` for every account, sorted by client, where `net` is
+    /// `gross_in - gross_out`. Disputes and chargebacks do not affect these figures.
+    pub fn write_net_output<W: io::Write>(&self, writer: W) -> Result<(), EngineError> {
+        self.write_rows(self.net_rows(), writer)
+    }
+
+    /// Cumulative deposits, withdrawals and their difference per client, sorted by client.
+    pub fn net_records(&self) -> Vec<NetRecord> {
+        self.net_rows().collect()
+    }
+
+    fn net_rows(&self) -> impl Iterator<Item = NetRecord> + '_ {
+        self.clients.iter().map(|(&client, account)| NetRecord {
+            client,
+            gross_in: self.config.output.format_amount(account.gross_in),
+            gross_out: self.config.output.format_amount(account.gross_out),
+            net: self
+                .config
+                .output
+                .format_amount(account.gross_in - account.gross_out),
+        })
+    }
+
+    /// Current balances of `client`, or `None` if no transaction has created the account.
+    pub fn account(&self, client: u16) -> Option<AccountSnapshot> {
+        self.clients.get(&client).map(|account| AccountSnapshot {
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.locked,
+        })
+    }
+
+    /// IDs of every known client, in ascending order.
+    pub fn client_ids(&self) -> impl Iterator<Item = u16> + '_ {
+        self.clients.keys().copied()
+    }
+
+    /// tx ID and amount still held for each open dispute of `client`, in ascending tx
+    /// order. Empty for an unknown client or one with nothing under dispute.
+    pub fn client_open_disputes(&self, client: u16) -> Vec<(u32, Decimal)> {
+        let mut disputes: Vec<_> = self
+            .clients
+            .get(&client)
+            .map(|account| {
+                account
+                    .holds
+                    .iter()
+                    .map(|(&tx, &held)| (tx, held))
+                    .collect()
+            })
+            .unwrap_or_default();
+        disputes.sort_unstable_by_key(|&(tx, _)| tx);
+        disputes
+    }
+
+    /// Accounts whose held balance exceeds `threshold`, largest held first; ties stay in
+    /// ascending client order.
+    pub fn holds_over(&self, threshold: Decimal) -> Vec<OutputRecord> {
+        let mut records = self.output_records();
+        records.retain(|record| record.held > threshold);
+        records.sort_by_key(|record| std::cmp::Reverse(record.held));
+        records
+    }
+
+    /// Final state of every account, one record per client, in ascending client order.
+    pub fn output_records(&self) -> Vec<OutputRecord> {
+        self.output_rows().collect()
+    }
+
+    fn output_rows(&self) -> impl Iterator<Item = OutputRecord> + '_ {
+        self.clients
+            .iter()
+            .map(|(&client_id, account)| OutputRecord {
+                client: client_id,
+                available: account.available,
+                held: account.held,
+                total: account.total,
+                locked: account.locked,
+                tx_count: account.tx_count,
+                scale: account.max_scale,
+            })
+    }
+
+    /// Write the final state of every account in ascending client order. Rows are
+    /// rendered and written one at a time straight from the account map, without
+    /// collecting them first.
+    pub fn write_output<W: io::Write>(&self, writer: W) -> Result<(), EngineError> {
+        self.write_rows(
+            self.output_rows()
+                .map(|record| self.config.output.format(&record)),
+            writer,
+        )
+    }
+
+    /// Serialize previously collected [`output_records`](Self::output_records), so the
+    /// same snapshot can be written to several sinks.
+    pub fn write_records<W: io::Write>(
+        &self,
+        records: &[OutputRecord],
+        writer: W,
+    ) -> Result<(), EngineError> {
+        self.write_rows(
+            records
+                .iter()
+                .map(|record| self.config.output.format(record)),
+            writer,
+        )
+    }
+
+    /// Accounts whose balances or lock state differ from `baseline`, in ascending client
+    /// order, for a delta report against an earlier run's output. Balances are compared
+    /// as they would be written, rounded to the output precision. Accounts missing from
+    /// `baseline` count as changed.
+    pub fn changed_since(&self, baseline: &HashMap<u16, AccountSnapshot>) -> Vec<OutputRecord> {
+        self.output_rows()
+            .filter(|record| {
+                let current = AccountSnapshot {
+                    available: self.config.output.round(record.available),
+                    held: self.config.output.round(record.held),
+                    total: self.config.output.round(record.total),
+                    locked: record.locked,
+                };
+                baseline.get(&record.client) != Some(&current)
+            })
+            .collect()
+    }
+
+    /// Every account in ascending client order as a JSON array of row objects, for
+    /// embedding in a larger document. Rows are rendered with this engine's output
+    /// options, so amounts are JSON strings, exactly as `--format json` writes them.
+    pub fn to_json_value(&self) -> serde_json::Value {
+        self.output_rows()
+            .map(|record| {
+                serde_json::to_value(self.config.output.format(&record))
+                    .expect("formatted rows hold only strings, integers and booleans")
+            })
+            .collect()
+    }
+
+    /// Render `records` with this engine's output options.
+    pub fn formatted_records(&self, records: &[OutputRecord]) -> Vec<FormattedRecord> {
+        records
+            .iter()
+            .map(|record| self.config.output.format(record))
+            .collect()
+    }
+
+    /// Write `rows` in the configured [`OutputFormat`] as they are produced. CSV output
+    /// honors the flush interval; JSON is written as a single array followed by a newline.
+    fn write_rows<T: Serialize, W: io::Write>(
+        &self,
+        rows: impl IntoIterator<Item = T>,
+        mut writer: W,
+    ) -> Result<(), EngineError> {
+        if self.config.output.output_format == OutputFormat::Json {
+            writer.write_all(b"[")?;
+            for (idx, row) in rows.into_iter().enumerate() {
+                if idx > 0 {
+                    writer.write_all(b",")?;
+                }
+                serde_json::to_writer(&mut writer, &row)?;
+            }
+            writer.write_all(b"]\n")?;
+            writer.flush()?;
+            return Ok(());
+        }
+
+        let mut wtr = csv::Writer::from_writer(writer);
+
+        for (count, row) in (1..).zip(rows) {
+            wtr.serialize(row)?;
+
+            if self
+                .config
+                .output
+                .flush_interval
+                .is_some_and(|interval| count % interval == 0)
+            {
+                wtr.flush()?;
+            }
+        }
+
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+/// Close every dispute still open on a just-locked account as if it were resolved:
+/// disputed deposits return to available and disputed withdrawals stand. A locked
+/// account accepts no further resolves or chargebacks, so these disputes could never
+/// complete otherwise. Returns the tx IDs of the closed disputes.
+fn resolve_pending_disputes(
+    account: &mut ClientAccount,
+    transactions: &mut impl TxStore,
+) -> Result<Vec<u32>, TransactionRejection> {
+    let mut pending: Vec<(u32, Decimal)> = account
+        .holds
+        .iter()
+        .map(|(&tx, &hold)| (tx, hold))
+        .collect();
+    pending.sort_unstable_by_key(|&(tx, _)| tx);
+
+    for &(tx, hold) in &pending {
+        let Some(stored) = transactions.get(tx) else {
+            continue;
+        };
+        account.release(tx, stored.kind, hold)?;
+        transactions.set_disputed(tx, false);
+    }
+    Ok(pending.into_iter().map(|(tx, _)| tx).collect())
+}
+
+/// Put `tx` back in the eviction queue if it was set aside while under dispute. It
+/// rejoins as the newest entry, as its dispute only just closed.
+fn unpin(tx: u32, pinned: &mut HashSet<u32>, stored_order: &mut VecDeque<u32>) {
+    if pinned.remove(&tx) {
+        stored_order.push_back(tx);
+    }
+}
+
+/// tx IDs of the deposits disputed on `account`, in ascending order: the holds a
+/// withdrawal may draw on under [`WithdrawalPolicy::AvailableThenHeld`].
+fn disputed_deposits(account: &ClientAccount, transactions: &impl TxStore) -> Vec<u32> {
+    let mut txs: Vec<u32> = account
+        .holds
+        .keys()
+        .copied()
+        .filter(|&tx| {
+            transactions
+                .get(tx)
+                .is_some_and(|stored| stored.kind == StoredKind::Deposit)
+        })
+        .collect();
+    txs.sort_unstable();
+    txs
+}
+
+/// How much of the hold on `record.tx` a resolve or chargeback settles: the amount on
+/// the row for a partial settlement, otherwise the whole hold.
+fn settled_amount(
+    record: &TransactionRecord,
+    account: &ClientAccount,
+) -> Result<Decimal, TransactionRejection> {
+    let hold = account.holds.get(&record.tx).copied().unwrap_or_default();
+    match record.amount {
+        None => Ok(hold),
+        Some(amount) if amount <= Decimal::ZERO => Err(TransactionRejection::NonPositiveAmount),
+        Some(amount) if amount > hold => Err(TransactionRejection::PartialExceedsHold),
+        Some(amount) => Ok(amount),
+    }
+}
+
+/// Emit a `tracing` event for a rejected record.
+#[cfg(feature = "tracing")]
+fn trace_rejection(record: &TransactionRecord, rejection: &TransactionRejection) {
+    tracing::debug!(
+        r#type = ?record.r#type,
+        client = record.client,
+        tx = record.tx,
+        %rejection,
+        "transaction rejected"
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::num::NonZeroUsize;
+    use std::str::FromStr;
+
+    use super::*;
+    use crate::types::AMOUNT_SCALE;
+
+    /// Build a record, parsing `amount` as a decimal literal.
+    fn record(
+        r#type: TransactionType,
+        client: u16,
+        tx: u32,
+        amount: Option<&str>,
+    ) -> TransactionRecord {
+        TransactionRecord {
+            r#type,
+            client,
+            tx,
+            amount: amount.map(|a| Decimal::from_str(a).unwrap()),
+        }
+    }
+
+    /// Run every record through a fresh engine.
+    fn engine_with(records: &[TransactionRecord]) -> PaymentsEngine {
+        let mut engine = PaymentsEngine::new();
+        for record in records {
+            let _ = engine.process(record.clone());
+        }
+        engine
+    }
+
+    #[test]
+    fn ledger_hash_matches_for_identical_sequences() {
+        let records = [
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 2, 2, Some("5.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+        ];
+
+        let a = engine_with(&records);
+        let b = engine_with(&records);
+        assert_eq!(a.ledger_hash(), b.ledger_hash());
+        assert_ne!(a.ledger_hash(), PaymentsEngine::new().ledger_hash());
+    }
+
+    #[test]
+    fn ledger_hash_is_order_sensitive() {
+        let first = record(TransactionType::Deposit, 1, 1, Some("10.0"));
+        let second = record(TransactionType::Deposit, 2, 2, Some("5.0"));
+
+        let a = engine_with(&[first.clone(), second.clone()]);
+        let b = engine_with(&[second, first]);
+        assert_ne!(a.ledger_hash(), b.ledger_hash());
+    }
+
+    #[test]
+    fn ledger_hash_ignores_rejected_records() {
+        let deposit = record(TransactionType::Deposit, 1, 1, Some("10.0"));
+
+        let a = engine_with(std::slice::from_ref(&deposit));
+        let b = engine_with(&[
+            deposit,
+            record(TransactionType::Withdrawal, 1, 2, Some("50.0")),
+        ]);
+        assert_eq!(a.ledger_hash(), b.ledger_hash());
+    }
+
+    /// Writer whose consumer has gone away, as when stdout is piped into `head`.
+    struct ClosedPipe;
+
+    impl Write for ClosedPipe {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Err(io::Error::from(io::ErrorKind::BrokenPipe))
+        }
+    }
+
+    #[test]
+    fn write_output_to_closed_pipe_reports_broken_pipe() {
+        let engine = engine_with(&[record(TransactionType::Deposit, 1, 1, Some("10.0"))]);
+
+        let err = engine.write_output(ClosedPipe).unwrap_err();
+        assert!(err.is_broken_pipe());
+    }
+
+    /// Writer that counts how many times it is flushed.
+    #[derive(Default)]
+    struct FlushCounter {
+        flushes: usize,
+    }
+
+    impl Write for &mut FlushCounter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_output_flushes_every_interval() {
+        let records: Vec<_> = (1..=10)
+            .map(|client| record(TransactionType::Deposit, client, client.into(), Some("1.0")))
+            .collect();
+        let engine = engine_with(&records);
+
+        let mut baseline = FlushCounter::default();
+        engine.write_output(&mut baseline).unwrap();
+
+        let engine = engine.with_output_options(OutputOptions {
+            flush_interval: Some(NonZeroUsize::new(3).unwrap()),
+            ..OutputOptions::default()
+        });
+        let mut counter = FlushCounter::default();
+        engine.write_output(&mut counter).unwrap();
+
+        // Rows 3, 6 and 9 each trigger one extra flush on top of the end-of-output flushes.
+        assert_eq!(counter.flushes, baseline.flushes + 3);
+    }
+
+    #[test]
+    fn repeated_dispute_cycles_keep_scale_bounded() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("2.50000000")),
+            record(TransactionType::Deposit, 1, 2, Some("1.25")),
+        ]);
+        for _ in 0..10_000 {
+            engine
+                .process(record(TransactionType::Dispute, 1, 1, None))
+                .unwrap();
+            engine
+                .process(record(TransactionType::Resolve, 1, 1, None))
+                .unwrap();
+        }
+
+        let account = &engine.clients[&1];
+        assert_eq!(account.available, Decimal::from_str("3.75").unwrap());
+        assert_eq!(account.held, Decimal::ZERO);
+        assert!(account.available.scale() <= AMOUNT_SCALE);
+        assert!(account.held.scale() <= AMOUNT_SCALE);
+
+        // Each cycle disputes a new withdrawal and resolves it in two parts written at
+        // twelve places, so every release would leave held and total past AMOUNT_SCALE
+        let mut engine = engine_with(&[record(TransactionType::Deposit, 1, 1, Some("1000"))]);
+        let within_scale = |account: &ClientAccount| {
+            [account.available, account.held, account.total]
+                .iter()
+                .all(|value| value.scale() <= AMOUNT_SCALE)
+        };
+        for tx in 2..1_002 {
+            for r in [
+                record(TransactionType::Withdrawal, 1, tx, Some("0.5")),
+                record(TransactionType::Dispute, 1, tx, None),
+                record(TransactionType::Resolve, 1, tx, Some("0.250000000000")),
+                record(TransactionType::Resolve, 1, tx, Some("0.250000000000")),
+            ] {
+                engine.process(r).unwrap();
+                assert!(within_scale(&engine.clients[&1]), "tx {tx}");
+            }
+        }
+
+        let account = &engine.clients[&1];
+        assert_eq!(account.available, Decimal::from(500));
+        assert_eq!(account.total, Decimal::from(500));
+        assert_eq!(account.held, Decimal::ZERO);
+        assert!(account.holds.is_empty());
+    }
+
+    #[test]
+    fn resolving_one_dispute_releases_only_its_hold() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 1, 2, Some("4.5")),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Dispute, 1, 2, None),
+        ]);
+        assert_eq!(engine.clients[&1].held, Decimal::from_str("14.5").unwrap());
+
+        engine
+            .process(record(TransactionType::Resolve, 1, 2, None))
+            .unwrap();
+
+        let account = &engine.clients[&1];
+        assert_eq!(account.held, Decimal::from_str("10.0").unwrap());
+        assert_eq!(account.available, Decimal::from_str("4.5").unwrap());
+        assert_eq!(account.holds.len(), 1);
+        assert_eq!(account.holds[&1], Decimal::from_str("10.0").unwrap());
+    }
+
+    #[test]
+    fn chargeback_records_lock_index() {
+        let engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 2, 2, Some("3.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Chargeback, 1, 1, None),
+            record(TransactionType::Deposit, 2, 3, Some("1.0")),
+        ]);
+
+        assert_eq!(engine.clients[&1].locked_at, Some(3));
+        assert_eq!(engine.clients[&2].locked_at, None);
+
+        let mut buf = Vec::new();
+        engine.write_lock_log(&mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), "client,locked_at\n1,3\n");
+    }
+
+    #[test]
+    fn chargeback_log_has_one_entry_per_applied_chargeback() {
+        let records = [
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 2, 2, Some("3.0")),
+            record(TransactionType::Chargeback, 2, 2, None),
+            record(TransactionType::Dispute, 2, 2, None),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Chargeback, 2, 2, None),
+            record(TransactionType::Chargeback, 1, 1, None),
+            record(TransactionType::Chargeback, 1, 1, None),
+        ];
+        let expected = [
+            ChargebackEvent {
+                client: 2,
+                tx: 2,
+                amount: Decimal::from_str("3.0").unwrap(),
+                sequence_index: 5,
+            },
+            ChargebackEvent {
+                client: 1,
+                tx: 1,
+                amount: Decimal::from_str("10.0").unwrap(),
+                sequence_index: 6,
+            },
+        ];
+
+        let mut engine = PaymentsEngine::new().with_chargeback_log(true);
+        engine.process_batch(&records);
+        assert_eq!(engine.chargeback_log(), expected);
+
+        let mut parallel = PaymentsEngine::new().with_chargeback_log(true);
+        parallel.process_parallel(records.clone(), NonZeroUsize::new(2).unwrap());
+        assert_eq!(parallel.chargeback_log(), expected);
+
+        let mut buf = Vec::new();
+        engine.write_chargeback_log(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client,tx,amount,sequence_index\n2,2,3.0,5\n1,1,10.0,6\n"
+        );
+        assert!(engine_with(&records).chargeback_log().is_empty());
+    }
+
+    #[test]
+    fn chargeback_log_uses_input_row_when_given() {
+        let mut engine = PaymentsEngine::new().with_chargeback_log(true);
+        for (row, r) in [
+            (0, record(TransactionType::Deposit, 1, 1, Some("10.0"))),
+            (3, record(TransactionType::Dispute, 1, 1, None)),
+            (7, record(TransactionType::Chargeback, 1, 1, None)),
+        ] {
+            engine.process_row(r, row).unwrap();
+        }
+
+        assert_eq!(engine.chargeback_log()[0].sequence_index, 7);
+        assert_eq!(engine.records_processed(), 3);
+    }
+
+    #[test]
+    fn process_batch_reports_rejections_by_index() {
+        let mut engine = PaymentsEngine::new();
+        let rejections = engine.process_batch(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Withdrawal, 1, 2, Some("25.0")),
+            record(TransactionType::Withdrawal, 1, 3, Some("4.0")),
+        ]);
+
+        assert_eq!(
+            rejections,
+            vec![(1, TransactionRejection::InsufficientFunds)]
+        );
+        assert_eq!(
+            engine.clients[&1].available,
+            Decimal::from_str("6.0").unwrap()
+        );
+    }
+
+    #[test]
+    fn decimal_comma_output() {
+        let options = OutputOptions {
+            decimal_comma: true,
+            ..OutputOptions::default()
+        };
+        let amount = Decimal::from_str("1234.56").unwrap();
+        assert_eq!(options.format_amount(amount), "1234,56");
+
+        let grouped = OutputOptions {
+            group_thousands: true,
+            ..options.clone()
+        };
+        assert_eq!(grouped.format_amount(amount), "1.234,56");
+        assert_eq!(
+            grouped.format_amount(Decimal::from_str("-1234567").unwrap()),
+            "-1.234.567"
+        );
+
+        let engine = engine_with(&[record(TransactionType::Deposit, 1, 1, Some("1234.56"))])
+            .with_output_options(options);
+        let mut buf = Vec::new();
+        engine.write_output(&mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "client,available,held,total,locked\n1,\"1234,56\",0,\"1234,56\",false\n"
+        );
+    }
+
+    #[test]
+    fn verify_consistency_detects_injected_inconsistency() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 1, 2, Some("5.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+        ]);
+        assert_eq!(engine.verify_consistency(), Ok(()));
+
+        // A dispute flag with no matching hold on the account.
+        engine.transactions.get_mut(&2).unwrap().under_dispute = true;
+        let err = engine.verify_consistency().unwrap_err();
+        assert!(err.contains("tx 2"), "{err}");
+
+        engine.transactions.get_mut(&2).unwrap().under_dispute = false;
+        engine.clients.get_mut(&1).unwrap().held = Decimal::ONE;
+        let err = engine.verify_consistency().unwrap_err();
+        assert!(err.contains("client 1"), "{err}");
+    }
+
+    #[test]
+    fn verify_consistency_flags_available_above_total() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+        ]);
+        assert_eq!(engine.verify_consistency(), Ok(()));
+
+        // A hold applied with the wrong sign: held goes negative and available grows.
+        let account = engine.clients.get_mut(&1).unwrap();
+        account.held = -account.held;
+        account.available = account.total - account.held;
+        let err = engine.verify_consistency().unwrap_err();
+        assert_eq!(err, "client 1 has 20.0 available but a total of only 10.0");
+    }
+
+    #[test]
+    fn pad_scale_pads_without_rounding() {
+        let options = OutputOptions {
+            pad_scale: Some(4),
+            ..OutputOptions::default()
+        };
+        assert_eq!(options.format_amount(Decimal::from(5)), "5.0000");
+
+        // Padding past the precision raises it rather than rounding first
+        let options = OutputOptions {
+            pad_scale: Some(6),
+            ..OutputOptions::default()
+        };
+        assert_eq!(
+            options.format_amount(Decimal::from_str("5.12345").unwrap()),
+            "5.123450"
+        );
+
+        let options = OutputOptions {
+            pad_scale: Some(2),
+            ..OutputOptions::default()
+        };
+        assert_eq!(options.format_amount(Decimal::from(5)), "5.00");
+        assert_eq!(
+            options.format_amount(Decimal::from_str("5.123").unwrap()),
+            "5.123"
+        );
+    }
+
+    #[test]
+    fn changed_since_reports_differing_and_new_accounts() {
+        let engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.00001")),
+            record(TransactionType::Deposit, 2, 2, Some("5.0")),
+            record(TransactionType::Deposit, 3, 3, Some("1.0")),
+        ]);
+        let snapshot = |available: &str, locked| AccountSnapshot {
+            available: Decimal::from_str(available).unwrap(),
+            held: Decimal::ZERO,
+            total: Decimal::from_str(available).unwrap(),
+            locked,
+        };
+        let baseline = HashMap::from([
+            // Equal once rounded to four places
+            (1, snapshot("10", false)),
+            (2, snapshot("5", true)),
+        ]);
+
+        let clients: Vec<u16> = engine
+            .changed_since(&baseline)
+            .iter()
+            .map(|record| record.client)
+            .collect();
+        assert_eq!(clients, [2, 3]);
+    }
+
+    #[test]
+    fn to_json_value_is_an_array_of_rows() {
+        let engine = engine_with(&[
+            record(TransactionType::Deposit, 2, 1, Some("1.5")),
+            record(TransactionType::Deposit, 1, 2, Some("10.1234")),
+            record(TransactionType::Dispute, 1, 2, None),
+        ]);
+
+        let value = engine.to_json_value();
+        let rows = value.as_array().unwrap();
+        assert_eq!(rows.len(), 2);
+        assert_eq!(
+            rows[0],
+            serde_json::json!({
+                "client": 1,
+                "available": "0.0000",
+                "held": "10.1234",
+                "total": "10.1234",
+                "locked": false,
+            })
+        );
+        assert_eq!(rows[1]["client"], 2);
+        assert_eq!(rows[1]["available"], "1.5");
+    }
+
+    #[test]
+    fn output_format_parses_names_case_insensitively() {
+        assert_eq!("csv".parse::<OutputFormat>().unwrap(), OutputFormat::Csv);
+        assert_eq!("JSON".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert!(matches!(
+            "xml".parse::<OutputFormat>(),
+            Err(EngineError::UnknownFormat(name)) if name == "xml"
+        ));
+    }
+
+    #[test]
+    fn precision_rounds_midpoints_away_from_zero() {
+        let two = OutputOptions::default().with_precision(2).unwrap();
+        assert_eq!(
+            two.format_amount(Decimal::from_str("0.125").unwrap()),
+            "0.13"
+        );
+        assert_eq!(
+            two.format_amount(Decimal::from_str("-0.125").unwrap()),
+            "-0.13"
+        );
+        assert_eq!(two.format_amount(Decimal::from_str("1.5").unwrap()), "1.5");
+
+        let zero = OutputOptions::default().with_precision(0).unwrap();
+        assert_eq!(zero.format_amount(Decimal::from_str("2.5").unwrap()), "3");
+        assert_eq!(
+            zero.format_amount(Decimal::from_str("2.4999").unwrap()),
+            "2"
+        );
+
+        assert!(matches!(
+            OutputOptions::default().with_precision(29),
+            Err(EngineError::InvalidPrecision(29))
+        ));
+    }
+
+    #[test]
+    fn preserve_scale_formats_to_most_precise_deposit() {
+        let engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("5.00")),
+            record(TransactionType::Deposit, 1, 2, Some("2.5")),
+            record(TransactionType::Deposit, 2, 3, Some("1.5")),
+            record(TransactionType::Deposit, 2, 4, Some("1.5")),
+            record(TransactionType::Withdrawal, 2, 5, Some("0.125")),
+        ]);
+        assert_eq!(engine.clients[&1].max_scale, 2);
+
+        let mut out = Vec::new();
+        engine.write_output(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n1,7.50,0,7.50,false\n2,2.875,0,2.875,false\n"
+        );
+
+        let engine = engine.with_output_options(OutputOptions {
+            preserve_scale: true,
+            ..OutputOptions::default()
+        });
+        let mut out = Vec::new();
+        engine.write_output(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "client,available,held,total,locked\n1,7.50,0.00,7.50,false\n2,2.875,0.0,2.875,false\n"
+        );
+    }
+
+    #[test]
+    fn snapshot_round_trip_resumes_processing() {
+        let records = [
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 2, 2, Some("5.0")),
+            record(TransactionType::Withdrawal, 1, 3, Some("2.5")),
+            record(TransactionType::Dispute, 2, 2, None),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Resolve, 1, 1, Some("4.0")),
+            record(TransactionType::Chargeback, 2, 2, None),
+            record(TransactionType::Deposit, 1, 1, Some("99.0")),
+        ];
+        let uninterrupted = engine_with(&records);
+
+        let (first, rest) = records.split_at(records.len() / 2);
+        let json = serde_json::to_string(&engine_with(first).to_snapshot()).unwrap();
+        let mut resumed = PaymentsEngine::from_snapshot(serde_json::from_str(&json).unwrap());
+        for r in rest {
+            let _ = resumed.process(r.clone());
+        }
+
+        assert_eq!(resumed.output_records(), uninterrupted.output_records());
+        assert_eq!(resumed.ledger_hash(), uninterrupted.ledger_hash());
+        assert_eq!(
+            resumed.records_processed(),
+            uninterrupted.records_processed()
+        );
+        assert_eq!(resumed.clients[&1].holds, uninterrupted.clients[&1].holds);
+        assert_eq!(resumed.clients[&2].locked_at, Some(6));
+    }
+
+    #[test]
+    fn cloned_engine_is_independent() {
+        let original = engine_with(&[record(TransactionType::Deposit, 1, 1, Some("10.0"))]);
+
+        let mut what_if = original.clone();
+        what_if
+            .process(record(TransactionType::Withdrawal, 1, 2, Some("4.0")))
+            .unwrap();
+
+        assert_eq!(
+            what_if.clients[&1].available,
+            Decimal::from_str("6.0").unwrap()
+        );
+        assert_eq!(
+            original.clients[&1].available,
+            Decimal::from_str("10.0").unwrap()
+        );
+        assert_ne!(what_if.ledger_hash(), original.ledger_hash());
+    }
+
+    #[test]
+    fn dispute_of_non_positive_stored_amount_is_rejected() {
+        for corrupt in ["0", "-5.0"] {
+            let mut engine = engine_with(&[record(TransactionType::Deposit, 1, 1, Some("10.0"))]);
+            engine.transactions.get_mut(&1).unwrap().amount = Decimal::from_str(corrupt).unwrap();
+
+            let rejection = engine
+                .process(record(TransactionType::Dispute, 1, 1, None))
+                .unwrap_err();
+            assert_eq!(rejection, TransactionRejection::NonPositiveStoredAmount);
+            assert!(rejection.warrants_warning());
+
+            let account = &engine.clients[&1];
+            assert_eq!(account.held, Decimal::ZERO);
+            assert_eq!(account.available, Decimal::from_str("10.0").unwrap());
+            assert!(account.holds.is_empty());
+            assert!(!engine.transactions[&1].under_dispute);
+        }
+    }
+
+    #[test]
+    fn stats_aggregate_accounts_and_disputes() {
+        let engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 2, 2, Some("5.5")),
+            record(TransactionType::Deposit, 2, 3, Some("1.0")),
+            record(TransactionType::Deposit, 3, 4, Some("2.0")),
+            record(TransactionType::Withdrawal, 1, 5, Some("2.5")),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Dispute, 2, 2, None),
+            record(TransactionType::Dispute, 3, 4, None),
+            record(TransactionType::Chargeback, 3, 4, None),
+        ]);
+
+        let stats = engine.stats().unwrap();
+        assert_eq!(
+            stats,
+            EngineStats {
+                accounts: 3,
+                total: Decimal::from_str("14.0").unwrap(),
+                locked: 1,
+                open_disputes: 2,
+            }
+        );
+        assert_eq!(engine.to_snapshot().summary().unwrap(), stats);
+    }
+
+    #[test]
+    fn stats_report_overflowing_total() {
+        let engine = engine_with(&[
+            record(
+                TransactionType::Deposit,
+                1,
+                1,
+                Some("60000000000000000000000000000"),
+            ),
+            record(
+                TransactionType::Deposit,
+                2,
+                2,
+                Some("60000000000000000000000000000"),
+            ),
+        ]);
+
+        assert!(matches!(
+            engine.stats(),
+            Err(EngineError::TotalOverflow("account totals"))
+        ));
+        assert!(matches!(
+            engine.to_snapshot().summary(),
+            Err(EngineError::TotalOverflow("account totals"))
+        ));
+    }
+
+    #[test]
+    fn reconciliation_gap_equals_charged_back_deposits() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 2, 2, Some("4.0")),
+            record(TransactionType::Withdrawal, 1, 3, Some("2.5")),
+            record(TransactionType::Withdrawal, 2, 4, Some("9.0")),
+            record(TransactionType::Dispute, 2, 2, None),
+        ]);
+        let before = engine.reconciliation().unwrap();
+        assert_eq!(before.net, Decimal::from_str("11.5").unwrap());
+        assert_eq!(before.total, before.net);
+        assert_eq!(before.difference, Decimal::ZERO);
+
+        engine
+            .process(record(TransactionType::Chargeback, 2, 2, None))
+            .unwrap();
+        let after = engine.reconciliation().unwrap();
+        assert_eq!(after.net, before.net);
+        assert_eq!(after.difference, Decimal::from_str("-4.0").unwrap());
+    }
+
+    #[test]
+    fn open_dispute_cap_spans_all_clients() {
+        let mut engine = PaymentsEngine::new().with_max_open_disputes(Some(2));
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 2, 2, Some("5.0")),
+            record(TransactionType::Deposit, 3, 3, Some("1.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Dispute, 2, 2, None),
+        ] {
+            engine.process(r).unwrap();
+        }
+
+        let rejection = engine
+            .process(record(TransactionType::Dispute, 3, 3, None))
+            .unwrap_err();
+        assert_eq!(rejection, TransactionRejection::DisputeLimitReached);
+        assert!(rejection.warrants_warning());
+        assert_eq!(engine.clients[&3].held, Decimal::ZERO);
+
+        // A partial resolve leaves the dispute open; a full one frees its slot.
+        engine
+            .process(record(TransactionType::Resolve, 1, 1, Some("4.0")))
+            .unwrap();
+        assert_eq!(
+            engine.process(record(TransactionType::Dispute, 3, 3, None)),
+            Err(TransactionRejection::DisputeLimitReached)
+        );
+        engine
+            .process(record(TransactionType::Resolve, 1, 1, None))
+            .unwrap();
+        engine
+            .process(record(TransactionType::Dispute, 3, 3, None))
+            .unwrap();
+
+        engine
+            .process(record(TransactionType::Chargeback, 2, 2, None))
+            .unwrap();
+        assert_eq!(engine.open_disputes, 1);
+    }
+
+    #[test]
+    fn builder_configs_change_engine_behavior() {
+        let spent_deposit = [
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Withdrawal, 1, 2, Some("7.0")),
+            record(TransactionType::Deposit, 1, 3, Some("0.125")),
+            record(TransactionType::Deposit, 1, 4, Some("0.5")),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Dispute, 1, 4, None),
+        ];
+        let run = |builder: PaymentsEngineBuilder| {
+            let mut engine = builder.build().unwrap();
+            let rejections: Vec<TransactionRejection> = spent_deposit
+                .iter()
+                .filter_map(|r| engine.process(r.clone()).err())
+                .collect();
+            let mut out = Vec::new();
+            engine.write_output(&mut out).unwrap();
+            (rejections, String::from_utf8(out).unwrap())
+        };
+
+        let (rejections, output) = run(PaymentsEngine::builder());
+        assert!(rejections.is_empty());
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,-6.875,10.5,3.625,false\n"
+        );
+
+        let (rejections, output) = run(PaymentsEngine::builder()
+            .allow_negative_available(false)
+            .precision(2));
+        assert_eq!(rejections, [TransactionRejection::DisputeExceedsAvailable]);
+        assert_eq!(
+            output,
+            "client,available,held,total,locked\n1,3.13,0.5,3.63,false\n"
+        );
+
+        let (rejections, _) = run(PaymentsEngine::builder().max_client_disputes(Some(1)));
+        assert_eq!(
+            rejections,
+            [TransactionRejection::ClientDisputeLimitReached]
+        );
+
+        let defaults = PaymentsEngine::new();
+        assert!(defaults.config().allow_negative_available);
+        assert_eq!(defaults.config().max_client_disputes, None);
+        assert!(matches!(
+            PaymentsEngine::builder().precision(29).build(),
+            Err(EngineError::InvalidPrecision(29))
+        ));
+    }
+
+    #[test]
+    fn client_dispute_cap_counts_only_that_clients_disputes() {
+        let mut engine = PaymentsEngine::new().with_max_client_disputes(Some(2));
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 1, 2, Some("5.0")),
+            record(TransactionType::Deposit, 1, 3, Some("1.0")),
+            record(TransactionType::Deposit, 2, 4, Some("2.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Dispute, 1, 2, None),
+        ] {
+            engine.process(r).unwrap();
+        }
+
+        let rejection = engine
+            .process(record(TransactionType::Dispute, 1, 3, None))
+            .unwrap_err();
+        assert_eq!(rejection, TransactionRejection::ClientDisputeLimitReached);
+        assert!(rejection.warrants_warning());
+        assert_eq!(engine.clients[&1].held, Decimal::from(15));
+
+        // Other clients have slots of their own.
+        engine
+            .process(record(TransactionType::Dispute, 2, 4, None))
+            .unwrap();
+
+        engine
+            .process(record(TransactionType::Resolve, 1, 1, None))
+            .unwrap();
+        engine
+            .process(record(TransactionType::Dispute, 1, 3, None))
+            .unwrap();
+        assert_eq!(engine.clients[&1].held, Decimal::from(6));
+    }
+
+    #[test]
+    fn dispute_for_missing_account_is_rejected() {
+        let mut engine = engine_with(&[record(TransactionType::Deposit, 1, 1, Some("10.0"))]);
+        engine.clients.remove(&1);
+
+        assert_eq!(
+            engine.process(record(TransactionType::Dispute, 1, 1, None)),
+            Err(TransactionRejection::AccountNotFound)
+        );
+        assert!(!engine.transactions[&1].under_dispute);
+    }
+
+    #[test]
+    fn min_balance_rejects_withdrawals_below_floor() {
+        let mut engine = PaymentsEngine::new().with_min_balance(Some(Decimal::from(5)));
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some("20.0")),
+            record(TransactionType::Withdrawal, 1, 2, Some("15.0")),
+        ] {
+            engine.process(r).unwrap();
+        }
+        assert_eq!(engine.clients[&1].available, Decimal::from(5));
+
+        // Funds cover it, but available would drop to 4.99
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 3, Some("0.01"))),
+            Err(TransactionRejection::BelowMinimumBalance)
+        );
+        let account = &engine.clients[&1];
+        assert_eq!(account.available, Decimal::from(5));
+        assert_eq!(account.total, Decimal::from(5));
+        assert!(!engine.transactions.contains_key(&3));
+    }
+
+    #[test]
+    fn default_withdrawal_never_touches_held() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 1, 2, Some("5.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+        ]);
+
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 3, Some("6.0"))),
+            Err(TransactionRejection::InsufficientFunds)
+        );
+        engine
+            .process(record(TransactionType::Withdrawal, 1, 4, Some("5.0")))
+            .unwrap();
+
+        let account = &engine.clients[&1];
+        assert_eq!(account.available, Decimal::ZERO);
+        assert_eq!(account.held, Decimal::from_str("10.0").unwrap());
+        assert_eq!(account.holds[&1], Decimal::from_str("10.0").unwrap());
+    }
+
+    #[test]
+    fn available_then_held_withdrawal_covers_shortfall_from_held() {
+        let mut engine =
+            PaymentsEngine::new().with_withdrawal_policy(WithdrawalPolicy::AvailableThenHeld);
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 1, 2, Some("5.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Withdrawal, 1, 3, Some("8.0")),
+        ] {
+            engine.process(r).unwrap();
+        }
+
+        let account = &engine.clients[&1];
+        assert_eq!(account.available, Decimal::ZERO);
+        assert_eq!(account.held, Decimal::from_str("7.0").unwrap());
+        assert_eq!(account.total, Decimal::from_str("7.0").unwrap());
+        assert_eq!(account.holds[&1], Decimal::from_str("7.0").unwrap());
+        assert_eq!(engine.verify_consistency(), Ok(()));
+
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 4, Some("7.5"))),
+            Err(TransactionRejection::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn available_then_held_ignores_held_opening_balance() {
+        let mut engine =
+            PaymentsEngine::new().with_withdrawal_policy(WithdrawalPolicy::AvailableThenHeld);
+        engine
+            .seed_account(&OpeningBalance {
+                client: 1,
+                available: Decimal::from_str("2.0").unwrap(),
+                held: Decimal::from_str("10.0").unwrap(),
+                locked: false,
+            })
+            .unwrap();
+
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 1, Some("5.0"))),
+            Err(TransactionRejection::InsufficientFunds)
+        );
+        let account = &engine.clients[&1];
+        assert_eq!(account.available, Decimal::from_str("2.0").unwrap());
+        assert_eq!(account.held, Decimal::from_str("10.0").unwrap());
+        assert_eq!(account.total, Decimal::from_str("12.0").unwrap());
+    }
+
+    #[test]
+    fn available_then_held_ignores_disputed_withdrawal_credit() {
+        let mut engine =
+            PaymentsEngine::new().with_withdrawal_policy(WithdrawalPolicy::AvailableThenHeld);
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Withdrawal, 1, 2, Some("10.0")),
+            record(TransactionType::Dispute, 1, 2, None),
+        ] {
+            engine.process(r).unwrap();
+        }
+
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 3, Some("5.0"))),
+            Err(TransactionRejection::InsufficientFunds)
+        );
+        let account = &engine.clients[&1];
+        assert_eq!(account.available, Decimal::ZERO);
+        assert_eq!(account.held, Decimal::from_str("10.0").unwrap());
+        assert_eq!(account.holds[&2], Decimal::from_str("10.0").unwrap());
+        assert_eq!(engine.verify_consistency(), Ok(()));
+    }
+
+    #[test]
+    fn non_positive_amounts_leave_account_untouched() {
+        let mut engine = PaymentsEngine::new();
+        assert_eq!(
+            engine.process(record(TransactionType::Deposit, 1, 1, Some("-50.0"))),
+            Err(TransactionRejection::NonPositiveAmount)
+        );
+        assert_eq!(
+            engine.process(record(TransactionType::Deposit, 1, 2, Some("0"))),
+            Err(TransactionRejection::NonPositiveAmount)
+        );
+        assert!(!engine.clients.contains_key(&1));
+        assert!(!engine.transactions.contains_key(&1));
+
+        engine
+            .process(record(TransactionType::Deposit, 1, 3, Some("20.0")))
+            .unwrap();
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 4, Some("-5.0"))),
+            Err(TransactionRejection::NonPositiveAmount)
+        );
+        assert_eq!(
+            engine.clients[&1].available,
+            Decimal::from_str("20.0").unwrap()
+        );
+    }
+
+    #[test]
+    fn memory_limit_evicts_oldest_undisputed_transactions() {
+        let mut engine = PaymentsEngine::new().with_max_memory(Some(3 * STORED_ENTRY_BYTES));
+        for record in [
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 1, 2, Some("2.0")),
+            record(TransactionType::Deposit, 2, 3, Some("3.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Deposit, 2, 4, Some("4.0")),
+            record(TransactionType::Withdrawal, 2, 5, Some("1.0")),
+        ] {
+            engine.process(record).unwrap();
+        }
+
+        assert_eq!(engine.evicted(), 2);
+        let mut stored: Vec<_> = engine.transactions.keys().copied().collect();
+        stored.sort_unstable();
+        assert_eq!(stored, [1, 4, 5]);
+        assert_eq!(
+            engine.process(record(TransactionType::Dispute, 1, 2, None)),
+            Err(TransactionRejection::UnknownTransaction)
+        );
+
+        engine
+            .process(record(TransactionType::Chargeback, 1, 1, None))
+            .unwrap();
+        assert!(engine.clients[&1].locked);
+        assert_eq!(engine.clients[&1].total, Decimal::from_str("2.0").unwrap());
+    }
+
+    #[test]
+    fn memory_limit_sets_disputed_entries_aside_until_resolved() {
+        let mut engine = PaymentsEngine::new().with_max_memory(Some(2 * STORED_ENTRY_BYTES));
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Deposit, 1, 2, Some("2.0")),
+            record(TransactionType::Deposit, 1, 3, Some("3.0")),
+            record(TransactionType::Deposit, 1, 4, Some("4.0")),
+        ] {
+            engine.process(r).unwrap();
+        }
+
+        // tx 1 was inspected once and set aside rather than left at the front
+        assert_eq!(engine.pinned, HashSet::from([1]));
+        assert_eq!(engine.stored_order, [4]);
+        assert_eq!(engine.evicted(), 2);
+
+        // Once resolved it rejoins the queue as the newest entry
+        engine
+            .process(record(TransactionType::Resolve, 1, 1, None))
+            .unwrap();
+        assert!(engine.pinned.is_empty());
+        assert_eq!(engine.stored_order, [4, 1]);
+
+        engine
+            .process(record(TransactionType::Deposit, 1, 5, Some("5.0")))
+            .unwrap();
+        let mut stored: Vec<_> = engine.transactions.keys().copied().collect();
+        stored.sort_unstable();
+        assert_eq!(stored, [1, 5]);
+    }
+
+    #[test]
+    fn processed_tx_tracking_rejects_refed_deposit_after_eviction() {
+        let deposit = record(TransactionType::Deposit, 1, 1, Some("10.0"));
+
+        let mut untracked = PaymentsEngine::new().with_max_memory(Some(0));
+        untracked.process(deposit.clone()).unwrap();
+        untracked.process(deposit.clone()).unwrap();
+        assert_eq!(
+            untracked.clients[&1].available,
+            Decimal::from_str("20.0").unwrap()
+        );
+
+        let mut engine = PaymentsEngine::builder()
+            .max_memory(Some(0))
+            .track_processed_tx(true)
+            .build()
+            .unwrap();
+        assert!(engine.config().track_processed_tx);
+        engine.process(deposit.clone()).unwrap();
+        assert!(!engine.transactions.contains_key(&1));
+        assert_eq!(
+            engine.process(deposit),
+            Err(TransactionRejection::DuplicateTransaction)
+        );
+        assert_eq!(
+            engine.clients[&1].available,
+            Decimal::from_str("10.0").unwrap()
+        );
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_reports_handler_spans_and_rejections() {
+        use std::sync::{Arc, Mutex};
+        use tracing::field::{Field, Visit};
+        use tracing::span::{Attributes, Id, Record};
+        use tracing::{Event, Metadata, Subscriber};
+
+        /// Collects span names and the `rejection` field of every event.
+        #[derive(Clone, Default)]
+        struct Recorder {
+            spans: Arc<Mutex<Vec<&'static str>>>,
+            rejections: Arc<Mutex<Vec<String>>>,
+        }
+
+        impl Visit for Recorder {
+            fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+                if field.name() == "rejection" {
+                    self.rejections.lock().unwrap().push(format!("{value:?}"));
+                }
+            }
+        }
+
+        impl Subscriber for Recorder {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, span: &Attributes<'_>) -> Id {
+                let mut spans = self.spans.lock().unwrap();
+                spans.push(span.metadata().name());
+                Id::from_u64(spans.len() as u64)
+            }
+            fn record(&self, _: &Id, _: &Record<'_>) {}
+            fn record_follows_from(&self, _: &Id, _: &Id) {}
+            fn event(&self, event: &Event<'_>) {
+                event.record(&mut self.clone());
+            }
+            fn enter(&self, _: &Id) {}
+            fn exit(&self, _: &Id) {}
+        }
+
+        let recorder = Recorder::default();
+        tracing::subscriber::with_default(recorder.clone(), || {
+            let mut engine = PaymentsEngine::new();
+            engine.process_batch(&[
+                record(TransactionType::Deposit, 1, 1, Some("10.0")),
+                record(TransactionType::Withdrawal, 1, 2, Some("25.0")),
+                record(TransactionType::Dispute, 1, 1, None),
+                record(TransactionType::Deposit, 2, 3, Some("1.0")),
+                record(TransactionType::Deposit, 2, 3, Some("1.0")),
+            ]);
+        });
+
+        assert_eq!(
+            *recorder.spans.lock().unwrap(),
+            [
+                "process_batch",
+                "handle_deposit",
+                "handle_withdrawal",
+                "handle_dispute",
+                // The coalesced run of two deposits for client 2
+                "handle_deposit",
+                "handle_deposit",
+            ]
+        );
+        assert_eq!(
+            *recorder.rejections.lock().unwrap(),
+            [
+                TransactionRejection::InsufficientFunds.to_string(),
+                TransactionRejection::DuplicateTransaction.to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn integer_amounts_rejects_only_fractional_parts() {
+        let mut engine = PaymentsEngine::new().with_integer_amounts(true);
+        let rejections = engine.process_batch(&[
+            record(TransactionType::Deposit, 1, 1, Some("5")),
+            record(TransactionType::Deposit, 1, 2, Some("5.0")),
+            record(TransactionType::Deposit, 1, 3, Some("5.50")),
+            record(TransactionType::Withdrawal, 1, 4, Some("1.5")),
+        ]);
+        assert_eq!(
+            rejections,
+            [
+                (2, TransactionRejection::FractionalAmount),
+                (3, TransactionRejection::FractionalAmount),
+            ]
+        );
+        assert_eq!(engine.clients[&1].available, Decimal::from(10));
+        assert!(!engine.transactions.contains_key(&3));
+    }
+
+    #[test]
+    fn client_open_disputes_lists_held_amounts() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 1, 2, Some("4.5")),
+            record(TransactionType::Deposit, 2, 3, Some("7.0")),
+            record(TransactionType::Dispute, 1, 2, None),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Dispute, 2, 3, None),
+        ]);
+        assert_eq!(
+            engine.client_open_disputes(1),
+            [
+                (1, Decimal::from_str("10.0").unwrap()),
+                (2, Decimal::from_str("4.5").unwrap()),
+            ]
+        );
+
+        engine
+            .process(record(TransactionType::Resolve, 1, 1, None))
+            .unwrap();
+        assert_eq!(
+            engine.client_open_disputes(1),
+            [(2, Decimal::from_str("4.5").unwrap())]
+        );
+        assert!(engine.client_open_disputes(3).is_empty());
+    }
+
+    #[test]
+    fn process_reports_outcome_of_each_record() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Chargeback, 1, 1, None),
+        ]);
+
+        assert_eq!(
+            engine.process(record(TransactionType::Deposit, 2, 2, Some("5.0"))),
+            Ok(())
+        );
+        assert_eq!(
+            engine.process(record(TransactionType::Deposit, 1, 3, Some("5.0"))),
+            Err(TransactionRejection::AccountLocked)
+        );
+        assert_eq!(
+            engine.process(record(TransactionType::Dispute, 2, 99, None)),
+            Err(TransactionRejection::UnknownTransaction)
+        );
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 2, 4, None)),
+            Err(TransactionRejection::MissingAmount)
+        );
+        assert_eq!(
+            engine.process(record(TransactionType::Deposit, 2, 2, Some("5.0"))),
+            Err(TransactionRejection::DuplicateTransaction)
+        );
+    }
+
+    #[test]
+    fn zero_amount_deposit_is_not_disputable() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 1, 2, Some("0.00")),
+        ]);
+        assert!(!engine.transactions.contains_key(&2));
+        assert_eq!(
+            engine.process(record(TransactionType::Dispute, 1, 2, None)),
+            Err(TransactionRejection::UnknownTransaction)
+        );
+        assert_eq!(engine.open_disputes, 0);
+        assert_eq!(engine.clients[&1].held, Decimal::ZERO);
+    }
+
+    #[test]
+    fn duplicate_tx_id_keeps_first_deposit() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 2, 2, Some("3.0")),
+        ]);
+        assert_eq!(
+            engine.process(record(TransactionType::Deposit, 2, 1, Some("99.0"))),
+            Err(TransactionRejection::DuplicateTransaction)
+        );
+        assert_eq!(
+            engine.process(record(TransactionType::Withdrawal, 1, 1, Some("1.0"))),
+            Err(TransactionRejection::DuplicateTransaction)
+        );
+
+        assert_eq!(engine.transactions[&1].client, 1);
+        assert_eq!(engine.clients[&2].total, Decimal::from_str("3.0").unwrap());
+
+        engine
+            .process(record(TransactionType::Dispute, 1, 1, None))
+            .unwrap();
+        let account = &engine.clients[&1];
+        assert_eq!(account.held, Decimal::from_str("10.0").unwrap());
+        assert_eq!(account.available, Decimal::ZERO);
+    }
+
+    #[test]
+    fn max_balance_rejects_deposit_over_cap() {
+        let mut engine =
+            PaymentsEngine::new().with_max_balance(Some(Decimal::from_str("100").unwrap()));
+        engine
+            .process(record(TransactionType::Deposit, 1, 1, Some("60.0")))
+            .unwrap();
+        engine
+            .process(record(TransactionType::Deposit, 1, 2, Some("40.0")))
+            .unwrap();
+        assert_eq!(
+            engine.process(record(TransactionType::Deposit, 1, 3, Some("0.01"))),
+            Err(TransactionRejection::BalanceCapExceeded)
+        );
+        assert_eq!(
+            engine.process(record(TransactionType::Deposit, 2, 4, Some("100.5"))),
+            Err(TransactionRejection::BalanceCapExceeded)
+        );
+
+        assert_eq!(engine.clients[&1].total, Decimal::from_str("100").unwrap());
+        assert!(!engine.transactions.contains_key(&3));
+        assert!(!engine.clients.contains_key(&2));
+    }
+
+    #[test]
+    fn holds_over_lists_large_holds_descending() {
+        let engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("50.0")),
+            record(TransactionType::Deposit, 2, 2, Some("200.0")),
+            record(TransactionType::Deposit, 3, 3, Some("100.0")),
+            record(TransactionType::Deposit, 4, 4, Some("100.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Dispute, 2, 2, None),
+            record(TransactionType::Dispute, 3, 3, None),
+        ]);
+
+        let clients: Vec<u16> = engine
+            .holds_over(Decimal::from_str("50").unwrap())
+            .iter()
+            .map(|record| record.client)
+            .collect();
+        assert_eq!(clients, [2, 3]);
+    }
+
+    #[test]
+    fn disputed_withdrawal_resolve_keeps_withdrawal() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Withdrawal, 1, 2, Some("4.0")),
+            record(TransactionType::Dispute, 1, 2, None),
+        ]);
+        let account = &engine.clients[&1];
+        assert_eq!(account.available, Decimal::from_str("6.0").unwrap());
+        assert_eq!(account.held, Decimal::from_str("4.0").unwrap());
+        assert_eq!(account.total, Decimal::from_str("10.0").unwrap());
+
+        engine
+            .process(record(TransactionType::Resolve, 1, 2, None))
+            .unwrap();
+        let account = &engine.clients[&1];
+        assert_eq!(account.available, Decimal::from_str("6.0").unwrap());
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.total, Decimal::from_str("6.0").unwrap());
+        assert!(!account.locked);
+    }
+
+    #[test]
+    fn disputed_withdrawal_chargeback_returns_funds() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Withdrawal, 1, 2, Some("4.0")),
+            record(TransactionType::Dispute, 1, 2, None),
+        ]);
+        engine
+            .process(record(TransactionType::Chargeback, 1, 2, None))
+            .unwrap();
+
+        let account = &engine.clients[&1];
+        assert_eq!(account.available, Decimal::from_str("10.0").unwrap());
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.total, Decimal::from_str("10.0").unwrap());
+        assert!(account.locked);
+        assert_eq!(engine.verify_consistency(), Ok(()));
+    }
+
+    #[test]
+    fn batched_deposit_runs_match_record_by_record_processing() {
+        let mut records = Vec::new();
+        for tx in 1..=40u32 {
+            let client = (tx / 10) as u16 + 1;
+            let amount = format!("{}.{}", tx, tx % 7);
+            records.push(record(TransactionType::Deposit, client, tx, Some(&amount)));
+        }
+        records.extend([
+            record(TransactionType::Deposit, 1, 3, Some("5.0")),
+            record(TransactionType::Deposit, 1, 41, Some("-1.0")),
+            record(TransactionType::Deposit, 1, 42, None),
+            record(TransactionType::Dispute, 1, 2, None),
+            record(TransactionType::Chargeback, 1, 2, None),
+            record(TransactionType::Deposit, 1, 43, Some("7.25")),
+            record(TransactionType::Deposit, 2, 44, Some("0.125")),
+            record(TransactionType::Deposit, 2, 45, Some("5000")),
+            record(TransactionType::Withdrawal, 2, 46, Some("1.5")),
+        ]);
+
+        let mut sequential = PaymentsEngine::new().with_max_balance(Some(Decimal::from(1000)));
+        let expected: Vec<(usize, TransactionRejection)> = records
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, r)| sequential.process(r.clone()).err().map(|e| (idx, e)))
+            .collect();
+
+        let mut batched = PaymentsEngine::new().with_max_balance(Some(Decimal::from(1000)));
+        assert_eq!(batched.process_batch(&records), expected);
+
+        assert_eq!(batched.output_records(), sequential.output_records());
+        assert_eq!(batched.ledger_hash(), sequential.ledger_hash());
+        assert_eq!(batched.records_processed(), sequential.records_processed());
+    }
+
+    #[test]
+    fn overflowing_deposit_is_rejected_without_panicking() {
+        let half_max = "50000000000000000000000000000";
+        let mut engine = engine_with(&[record(TransactionType::Deposit, 1, 1, Some(half_max))]);
+
+        assert_eq!(
+            engine.process(record(TransactionType::Deposit, 1, 2, Some(half_max))),
+            Err(TransactionRejection::Overflow)
+        );
+        assert!(!engine.transactions.contains_key(&2));
+        assert_eq!(
+            engine.clients[&1].total,
+            Decimal::from_str(half_max).unwrap()
+        );
+
+        // Later rows are still processed
+        engine
+            .process(record(TransactionType::Deposit, 1, 3, Some("1")))
+            .unwrap();
+        engine
+            .process(record(TransactionType::Deposit, 2, 4, Some(half_max)))
+            .unwrap();
+        assert_eq!(
+            engine.process_batch(&[
+                record(TransactionType::Deposit, 2, 5, Some(half_max)),
+                record(TransactionType::Deposit, 2, 6, Some("2")),
+            ]),
+            [(0, TransactionRejection::Overflow)]
+        );
+        assert_eq!(
+            engine.clients[&2].total,
+            Decimal::from_str("50000000000000000000000000002").unwrap()
+        );
+    }
+
+    #[test]
+    fn tx_count_counts_only_applied_operations() {
+        let engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Withdrawal, 1, 2, Some("50.0")),
+            record(TransactionType::Dispute, 2, 1, None),
+            record(TransactionType::Resolve, 1, 1, None),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Chargeback, 1, 1, None),
+            record(TransactionType::Deposit, 1, 3, Some("5.0")),
+            record(TransactionType::Deposit, 2, 4, Some("1.0")),
+        ]);
+
+        // Deposit, dispute and chargeback; the rest were rejected or hit the locked account.
+        assert_eq!(engine.clients[&1].tx_count, 3);
+        assert_eq!(engine.clients[&2].tx_count, 1);
+    }
+
+    #[test]
+    fn replaying_event_log_reproduces_account_states() {
+        let mut engine = PaymentsEngine::new().with_event_log(true);
+        for r in [
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 2, 2, Some("4.0")),
+            record(TransactionType::Withdrawal, 1, 3, Some("99.0")),
+            record(TransactionType::Withdrawal, 1, 4, Some("2.5")),
+            record(TransactionType::Dispute, 1, 1, Some("123")),
+            record(TransactionType::Dispute, 2, 9, None),
+            record(TransactionType::Resolve, 1, 1, None),
+            record(TransactionType::Dispute, 2, 2, None),
+            record(TransactionType::Chargeback, 2, 2, None),
+            record(TransactionType::Deposit, 2, 5, Some("1.0")),
+        ] {
+            let _ = engine.process(r);
+        }
+
+        // Only applied transactions are logged, and dispute amounts are dropped.
+        assert_eq!(engine.events().len(), 7);
+        assert_eq!(engine.events()[3].amount, None);
+
+        let mut csv = Vec::new();
+        engine.write_events(&mut csv).unwrap();
+        let mut reader = csv::Reader::from_reader(csv.as_slice());
+        let mut replayed = PaymentsEngine::new();
+        for event in reader.deserialize::<LedgerEvent>() {
+            replayed.process(event.unwrap().into()).unwrap();
+        }
+
+        assert_eq!(replayed.output_records(), engine.output_records());
+    }
+
+    #[test]
+    fn resolve_and_chargeback_never_drive_held_negative() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+        ]);
+        // Simulate other logic having shrunk held below the tx's hold.
+        engine.clients.get_mut(&1).unwrap().held = Decimal::from(4);
+
+        assert_eq!(
+            engine.process(record(TransactionType::Resolve, 1, 1, None)),
+            Err(TransactionRejection::InsufficientHeld)
+        );
+        assert_eq!(
+            engine.process(record(TransactionType::Chargeback, 1, 1, None)),
+            Err(TransactionRejection::InsufficientHeld)
+        );
+
+        let account = &engine.clients[&1];
+        assert_eq!(account.held, Decimal::from(4));
+        assert!(!account.locked);
+        assert!(engine.transactions[&1].under_dispute);
+    }
+
+    #[test]
+    fn chargeback_resolves_other_open_disputes_on_the_account() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 1, 2, Some("20.0")),
+            record(TransactionType::Withdrawal, 1, 3, Some("5.0")),
+            record(TransactionType::Deposit, 2, 4, Some("7.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Dispute, 1, 3, None),
+            record(TransactionType::Dispute, 2, 4, None),
+        ]);
+        assert_eq!(engine.open_disputes, 3);
+
+        engine
+            .process(record(TransactionType::Chargeback, 1, 1, None))
+            .unwrap();
+
+        // tx 1 left the account; the withdrawal dispute on tx 3 was dropped, so the
+        // withdrawal stands. Client 2's dispute is untouched.
+        let account = &engine.clients[&1];
+        assert_eq!(account.available, Decimal::from_str("15.0").unwrap());
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.total, Decimal::from_str("15.0").unwrap());
+        assert!(account.holds.is_empty());
+        assert!(account.locked);
+        assert!(!engine.transactions[&3].under_dispute);
+        assert!(engine.transactions[&4].under_dispute);
+        assert_eq!(engine.open_disputes, 1);
+        assert_eq!(engine.verify_consistency(), Ok(()));
+
+        assert_eq!(
+            engine.process(record(TransactionType::Resolve, 1, 3, None)),
+            Err(TransactionRejection::NotDisputed)
+        );
+    }
+
+    #[test]
+    fn partial_settlements_are_bounded_by_the_tx_hold() {
+        let mut engine = engine_with(&[
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 1, 2, Some("20.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Dispute, 1, 2, None),
+        ]);
+
+        // Within the account's held balance, but more than tx 1 holds.
+        assert_eq!(
+            engine.process(record(TransactionType::Chargeback, 1, 1, Some("15.0"))),
+            Err(TransactionRejection::PartialExceedsHold)
+        );
+
+        engine
+            .process(record(TransactionType::Resolve, 1, 1, Some("4.0")))
+            .unwrap();
+        let account = &engine.clients[&1];
+        assert_eq!(account.available, Decimal::from_str("4.0").unwrap());
+        assert_eq!(account.holds[&1], Decimal::from_str("6.0").unwrap());
+        assert!(engine.transactions[&1].under_dispute);
+
+        assert_eq!(
+            engine.process(record(TransactionType::Chargeback, 1, 1, Some("6.5"))),
+            Err(TransactionRejection::PartialExceedsHold)
+        );
+        engine
+            .process(record(TransactionType::Chargeback, 1, 1, Some("5.0")))
+            .unwrap();
+
+        // 5 of tx 1 left the account and its last 1 was released. Locking the account
+        // resolved the dispute on tx 2.
+        let account = &engine.clients[&1];
+        assert_eq!(account.available, Decimal::from_str("25.0").unwrap());
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.total, Decimal::from_str("25.0").unwrap());
+        assert!(account.locked);
+        assert!(!engine.transactions[&1].under_dispute);
+        assert!(!engine.transactions[&2].under_dispute);
+        assert_eq!(engine.verify_consistency(), Ok(()));
+    }
+
+    #[test]
+    fn full_chargeback_after_partial_resolve_follows_remainder_policy() {
+        let records = [
+            record(TransactionType::Deposit, 1, 1, Some("10.0")),
+            record(TransactionType::Deposit, 1, 2, Some("5.0")),
+            record(TransactionType::Dispute, 1, 1, None),
+            record(TransactionType::Resolve, 1, 1, Some("4.0")),
+            record(TransactionType::Chargeback, 1, 1, None),
+        ];
+
+        // Only the 6 still held leaves; the 4 released earlier stays available.
+        let kept = engine_with(&records);
+        let account = &kept.clients[&1];
+        assert_eq!(account.available, Decimal::from_str("9.0").unwrap());
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.total, Decimal::from_str("9.0").unwrap());
+        assert!(account.locked);
+        assert!(account.released.is_empty());
+
+        let mut reversed =
+            PaymentsEngine::new().with_released_remainder(ReleasedRemainderPolicy::Reverse);
+        for record in &records[..4] {
+            reversed.process(record.clone()).unwrap();
+        }
+        assert_eq!(
+            reversed.clients[&1].released[&1],
+            Decimal::from_str("4.0").unwrap()
+        );
+        reversed.process(records[4].clone()).unwrap();
+        let account = &reversed.clients[&1];
+        assert_eq!(account.available, Decimal::from_str("5.0").unwrap());
+        assert_eq!(account.held, Decimal::ZERO);
+        assert_eq!(account.total, Decimal::from_str("5.0").unwrap());
+        assert_eq!(reversed.verify_consistency(), Ok(()));
+    }
+
+    #[test]
+    fn parallel_processing_matches_sequential() {
+        let mut records = Vec::new();
+        for tx in 1..=200u32 {
+            let client = (tx % 7) as u16;
+            let amount = format!("{}.{:02}", tx % 13 + 1, tx % 100);
+            let r#type = if tx % 5 == 0 {
+                TransactionType::Withdrawal
+            } else {
+                TransactionType::Deposit
+            };
+            records.push(record(r#type, client, tx, Some(&amount)));
+        }
+        for tx in (1..=200u32).step_by(9) {
+            let client = (tx % 7) as u16;
+            records.push(record(TransactionType::Dispute, client, tx, None));
+            let settle = if tx % 2 == 0 {
+                TransactionType::Resolve
+            } else {
+                TransactionType::Chargeback
+            };
+            records.push(record(settle, client, tx, None));
+            records.push(record(
+                TransactionType::Deposit,
+                client,
+                1000 + tx,
+                Some("1"),
+            ));
+        }
+
+        let mut sequential = PaymentsEngine::new();
+        let expected: Vec<usize> = sequential
+            .process_batch(&records)
+            .into_iter()
+            .map(|(idx, _)| idx)
+            .collect();
+
+        let mut parallel = PaymentsEngine::new();
+        let rejected: Vec<usize> = parallel
+            .process_parallel(records.iter().cloned(), NonZeroUsize::new(3).unwrap())
+            .into_iter()
+            .map(|(idx, _)| idx)
+            .collect();
+
+        assert_eq!(rejected, expected);
+        assert_eq!(parallel.output_records(), sequential.output_records());
+        assert_eq!(parallel.records_processed(), sequential.records_processed());
+
+        let lock_log = |engine: &PaymentsEngine| {
+            let mut buf = Vec::new();
+            engine.write_lock_log(&mut buf).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+        assert_eq!(lock_log(&parallel), lock_log(&sequential));
+        assert!(parallel.output_records().iter().any(|r| r.locked));
+    }
+
+    #[test]
+    fn parallel_output_is_byte_identical_to_sequential() {
+        // Clients spread over the whole u16 range, so every shard merges many accounts.
+        let mut records = Vec::new();
+        for tx in 0..30_000u32 {
+            let client = (tx.wrapping_mul(2_654_435_761) >> 16) as u16;
+            let amount = format!("{}.{:04}", tx % 97 + 1, tx % 10_000);
+            let r#type = if tx % 4 == 3 {
+                TransactionType::Withdrawal
+            } else {
+                TransactionType::Deposit
+            };
+            records.push(record(r#type, client, tx, Some(&amount)));
+            if tx % 11 == 0 {
+                records.push(record(TransactionType::Dispute, client, tx, None));
+            }
+        }
+
+        let write = |engine: &PaymentsEngine| {
+            let mut out = Vec::new();
+            engine.write_output(&mut out).unwrap();
+            out
+        };
+        let sequential = engine_with(&records);
+        for threads in [2, 7, 16] {
+            let mut parallel = PaymentsEngine::new();
+            parallel.process_parallel(records.iter().cloned(), NonZeroUsize::new(threads).unwrap());
+            assert!(write(&parallel) == write(&sequential), "{threads} threads");
+        }
+    }
+
+    /// Collects what reaches the writer between flushes.
+    #[derive(Default)]
+    struct ChunkRecorder {
+        pending: Vec<u8>,
+        chunks: Vec<String>,
+    }
+
+    impl Write for &mut ChunkRecorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.pending.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            if !self.pending.is_empty() {
+                let chunk = std::mem::take(&mut self.pending);
+                self.chunks.push(String::from_utf8(chunk).unwrap());
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_output_streams_rows_in_client_order() {
+        let engine = engine_with(&[
+            record(TransactionType::Deposit, 3, 1, Some("3.0")),
+            record(TransactionType::Deposit, 1, 2, Some("1.0")),
+            record(TransactionType::Deposit, 2, 3, Some("2.0")),
+        ])
+        .with_output_options(OutputOptions {
+            flush_interval: Some(NonZeroUsize::new(1).unwrap()),
+            ..OutputOptions::default()
+        });
+
+        let mut recorder = ChunkRecorder::default();
+        engine.write_output(&mut recorder).unwrap();
+        assert_eq!(
+            recorder.chunks,
+            [
+                "client,available,held,total,locked\n1,1.0,0,1.0,false\n",
+                "2,2.0,0,2.0,false\n",
+                "3,3.0,0,3.0,false\n",
+            ]
+        );
+    }
+
+    #[test]
+    fn write_output_streams_every_client_id_sorted() {
+        // Client IDs are u16, so this is the largest possible account map.
+        let mut engine = PaymentsEngine::new().with_output_options(OutputOptions {
+            flush_interval: Some(NonZeroUsize::new(4096).unwrap()),
+            ..OutputOptions::default()
+        });
+        for client in (0..=u16::MAX).rev() {
+            engine
+                .process(record(
+                    TransactionType::Deposit,
+                    client,
+                    client.into(),
+                    Some("1"),
+                ))
+                .unwrap();
+        }
+
+        let mut recorder = ChunkRecorder::default();
+        engine.write_output(&mut recorder).unwrap();
+        assert_eq!(recorder.chunks.len(), 16);
+
+        let output = recorder.chunks.concat();
+        let clients: Vec<u16> = output
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').next().unwrap().parse().unwrap())
+            .collect();
+        assert_eq!(clients.len(), 65_536);
+        assert!(clients.windows(2).all(|pair| pair[0] < pair[1]));
+    }
 }
@@ -5,6 +5,240 @@ pub enum EngineError {
     #[error("CSV error: {0}")]
     Csv(#[from] csv::Error),
 
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
+
+    #[error("cannot open {path}: {source}")]
+    OpenInput {
+        path: String,
+        source: std::io::Error,
+    },
+
+    #[error("engine state is inconsistent: {0}")]
+    Inconsistent(String),
+
+    #[error("precision {0} is out of range; at most 28 decimal places are supported")]
+    InvalidPrecision(u32),
+
+    #[error("invalid delimiter `{0}`; expected a single ASCII character")]
+    InvalidDelimiter(String),
+
+    #[error("unknown output format `{0}`; expected `csv` or `json`")]
+    UnknownFormat(String),
+
+    #[error("processing timed out after {0:?}; output is partial")]
+    Timeout(std::time::Duration),
+
+    #[error("input has no `{0}` column")]
+    MissingColumn(&'static str),
+
+    #[error("skipped {skipped} of {rows} rows in strict mode")]
+    SkippedRows { skipped: u64, rows: u64 },
+
+    #[error("none of the {count} input rows could be parsed")]
+    MalformedRows { count: u64 },
+
+    #[error("{malformed} of {rows} rows failed to parse")]
+    InvalidRows { malformed: u64, rows: u64 },
+
+    #[error(
+        "system-wide total fell to {total} at tx {tx} (record {index}); the input may be corrupt"
+    )]
+    NegativeSystemTotal {
+        tx: u32,
+        index: u64,
+        total: rust_decimal::Decimal,
+    },
+
+    #[error("{0} row(s) differ from the golden output")]
+    GoldenMismatch(usize),
+
+    #[error("sum of {0} is too large to represent")]
+    TotalOverflow(&'static str),
+
+    #[cfg(feature = "mmap")]
+    #[error("input file {0} changed while it was being read")]
+    InputChanged(String),
+}
+
+/// Why the engine declined to apply a transaction. Rejected records leave all
+/// balances untouched.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum TransactionRejection {
+    #[error("deposit or withdrawal has no amount")]
+    MissingAmount,
+
+    #[error("amount must be greater than zero")]
+    NonPositiveAmount,
+
+    #[error("amount has a fractional part, but only whole units are accepted")]
+    FractionalAmount,
+
+    #[error("transaction ID was already used")]
+    DuplicateTransaction,
+
+    #[error("deposit would exceed the maximum balance")]
+    BalanceCapExceeded,
+
+    #[error("amount would overflow the account balance")]
+    Overflow,
+
+    #[error("stored amount of the disputed transaction is not positive")]
+    NonPositiveStoredAmount,
+
+    #[error("held balance is smaller than the disputed amount")]
+    InsufficientHeld,
+
+    #[error("partial amount exceeds what is still held for the transaction")]
+    PartialExceedsHold,
+
+    #[error("too many disputes are already open")]
+    DisputeLimitReached,
+
+    #[error("client already has too many disputes open")]
+    ClientDisputeLimitReached,
+
+    #[error("available balance is smaller than the disputed amount")]
+    DisputeExceedsAvailable,
+
+    #[error("account is locked")]
+    AccountLocked,
+
+    #[error("insufficient available funds")]
+    InsufficientFunds,
+
+    #[error("withdrawal would leave available funds below the minimum balance")]
+    BelowMinimumBalance,
+
+    #[error("referenced transaction does not exist")]
+    UnknownTransaction,
+
+    #[error("referenced transaction has no matching client account")]
+    AccountNotFound,
+
+    #[error("referenced transaction belongs to another client")]
+    ClientMismatch,
+
+    #[error("transaction is already under dispute")]
+    AlreadyDisputed,
+
+    #[error("transaction is not under dispute")]
+    NotDisputed,
+}
+
+impl EngineError {
+    /// True when the underlying cause is a closed output pipe (e.g. piping into `head`),
+    /// which Unix tools treat as a normal early exit rather than a failure.
+    pub fn is_broken_pipe(&self) -> bool {
+        let io_err = match self {
+            EngineError::Io(e) => Some(e),
+            EngineError::Csv(e) => match e.kind() {
+                csv::ErrorKind::Io(e) => Some(e),
+                _ => None,
+            },
+            _ => None,
+        };
+        io_err.is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe)
+    }
+}
+
+impl TransactionRejection {
+    /// Whether the rejection points at bad input data that deserves a warning, as
+    /// opposed to the dispute-flow rejections the spec treats as silent no-ops.
+    pub fn warrants_warning(&self) -> bool {
+        matches!(
+            self,
+            TransactionRejection::MissingAmount
+                | TransactionRejection::NonPositiveAmount
+                | TransactionRejection::FractionalAmount
+                | TransactionRejection::DuplicateTransaction
+                | TransactionRejection::Overflow
+                | TransactionRejection::NonPositiveStoredAmount
+                | TransactionRejection::InsufficientHeld
+                | TransactionRejection::PartialExceedsHold
+                | TransactionRejection::DisputeLimitReached
+                | TransactionRejection::ClientDisputeLimitReached
+                | TransactionRejection::DisputeExceedsAvailable
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn engine_error_messages() {
+        let cases = [
+            (
+                EngineError::OpenInput {
+                    path: "day-2.csv".into(),
+                    source: std::io::Error::from(std::io::ErrorKind::NotFound),
+                },
+                "cannot open day-2.csv: entity not found",
+            ),
+            (
+                EngineError::Inconsistent("client 1 holds 5 but stores 4".into()),
+                "engine state is inconsistent: client 1 holds 5 but stores 4",
+            ),
+            (
+                EngineError::InvalidPrecision(29),
+                "precision 29 is out of range; at most 28 decimal places are supported",
+            ),
+            (
+                EngineError::UnknownFormat("xml".into()),
+                "unknown output format `xml`; expected `csv` or `json`",
+            ),
+            (
+                EngineError::Timeout(Duration::from_secs(2)),
+                "processing timed out after 2s; output is partial",
+            ),
+            (
+                EngineError::MissingColumn("amount"),
+                "input has no `amount` column",
+            ),
+            (
+                EngineError::SkippedRows {
+                    skipped: 3,
+                    rows: 6,
+                },
+                "skipped 3 of 6 rows in strict mode",
+            ),
+            (
+                EngineError::MalformedRows { count: 4 },
+                "none of the 4 input rows could be parsed",
+            ),
+            (
+                EngineError::NegativeSystemTotal {
+                    tx: 7,
+                    index: 3,
+                    total: rust_decimal::Decimal::new(-25, 1),
+                },
+                "system-wide total fell to -2.5 at tx 7 (record 3); the input may be corrupt",
+            ),
+            (
+                EngineError::InvalidRows {
+                    malformed: 2,
+                    rows: 5,
+                },
+                "2 of 5 rows failed to parse",
+            ),
+            (
+                EngineError::GoldenMismatch(2),
+                "2 row(s) differ from the golden output",
+            ),
+            (
+                EngineError::TotalOverflow("account totals"),
+                "sum of account totals is too large to represent",
+            ),
+        ];
+        for (error, message) in cases {
+            assert_eq!(error.to_string(), message);
+        }
+    }
 }
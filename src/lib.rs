@@ -0,0 +1,39 @@
+//! # Accounts Manager
+//!
+//! Library interface to the payments engine. The `accounts_manager` binary is
+//! a thin CLI over these types; other programs can drive the engine directly:
+//!
+//! ```
+//! use accounts_manager::{PaymentsEngine, TransactionRecord, TransactionType};
+//! use rust_decimal::Decimal;
+//!
+//! let mut engine = PaymentsEngine::new();
+//! engine
+//!     .process(TransactionRecord {
+//!         r#type: TransactionType::Deposit,
+//!         client: 1,
+//!         tx: 1,
+//!         amount: Some(Decimal::new(15, 1)),
+//!     })
+//!     .unwrap();
+//!
+//! let mut out = Vec::new();
+//! engine.write_output(&mut out).unwrap();
+//! assert_eq!(
+//!     String::from_utf8(out).unwrap(),
+//!     "client,available,held,total,locked\n1,1.5,0,1.5,false\n"
+//! );
+//! ```
+
+pub mod anomaly;
+pub mod engine;
+pub mod error;
+pub mod store;
+pub mod types;
+
+pub use engine::{
+    Config, EngineSnapshot, EngineStats, PaymentsEngine, PaymentsEngineBuilder, Reconciliation,
+};
+pub use error::EngineError;
+pub use store::TxStore;
+pub use types::{AccountSnapshot, ClientAccount, OutputRecord, TransactionRecord, TransactionType};
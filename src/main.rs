@@ -8,54 +8,1369 @@
 //!
 //! Saddam (Sam) Uwejan
 
-mod engine;
-mod error;
-mod types;
-
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::num::{NonZeroU16, NonZeroU32, NonZeroU64, NonZeroUsize};
+use std::path::{Path, PathBuf};
 use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use clap::{ArgAction, Parser, Subcommand};
+use csv::{ReaderBuilder, StringRecord};
+use flate2::read::GzDecoder;
+use rust_decimal::Decimal;
+use serde::Serialize;
 
-use clap::Parser;
-use csv::ReaderBuilder;
+use accounts_manager::anomaly::{AmountAnomalyDetector, ClientActivityMonitor};
+use accounts_manager::error::TransactionRejection;
+use accounts_manager::types::{
+    parse_fraction, BaselineRecord, OpeningBalance, OutputFormat, OutputOptions,
+    ReleasedRemainderPolicy, ResolutionRecord, TransactionRecord, TypeCase, TypeCounts,
+    WithdrawalPolicy, AMOUNT_SCALE, OUTPUT_SCHEMA_VERSION,
+};
+use accounts_manager::{
+    AccountSnapshot, EngineError, EngineSnapshot, OutputRecord, PaymentsEngine, TransactionType,
+};
 
-use engine::PaymentsEngine;
-use error::EngineError;
-use types::TransactionRecord;
+/// Input columns in the order a headerless file has them unless --field-order says otherwise.
+const INPUT_COLUMNS: [&str; 4] = ["type", "client", "tx", "amount"];
 
 #[derive(Parser)]
 #[command(name = "accounts_manager", author = "Saddam Uwejan")]
 #[command(about = "Process payment transactions and output client account states")]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
-    input_file: String,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Transactions CSVs, processed in order into one set of accounts; read from stdin
+    /// when omitted or given as `-`
+    input_file: Vec<String>,
+
+    /// Read every `.csv` member of a `.tar.gz` archive, in name order, instead of INPUT_FILE
+    #[cfg(feature = "tar")]
+    #[arg(long, value_name = "PATH", conflicts_with = "input_file")]
+    tar: Option<String>,
+
+    /// The input has no header row; columns are read by position, in the order given
+    /// by --field-order (`type,client,tx,amount` by default)
+    #[arg(long)]
+    no_headers: bool,
+
+    /// Column names of a headerless input in physical order, e.g. `client,type,tx,amount`
+    #[arg(long, value_name = "LIST", requires = "no_headers", value_parser = parse_field_order)]
+    field_order: Option<StringRecord>,
+
+    /// Decompress gzipped input, including stdin; implied for an INPUT_FILE ending in `.gz`
+    #[arg(long)]
+    gzip: bool,
+
+    /// Fail if the input header has no `amount` column, instead of skipping every row
+    /// as malformed
+    #[arg(long)]
+    require_amount_column: bool,
+
+    /// Accept amounts written as fractions (e.g. `1/3`), rounded to four decimal places
+    #[arg(long)]
+    allow_fractions: bool,
+
+    /// Skip rows whose amount has no decimal places (`5` instead of `5.00`)
+    #[arg(long)]
+    require_fractional: bool,
+
+    /// Skip, with a warning, deposits and withdrawals whose amount has a fractional part
+    /// (`5.50`), for inputs that should only hold whole units
+    #[arg(long)]
+    integer_amounts: bool,
+
+    /// Field delimiter of the input, e.g. `;`
+    #[arg(long, value_name = "CHAR", default_value = ",", value_parser = parse_delimiter)]
+    delimiter: u8,
+
+    /// Read input amounts with `,` as the decimal separator (`5,00`); `.` is then taken
+    /// as a thousands separator (`1.234,56`)
+    #[arg(long)]
+    decimal_comma: bool,
+
+    /// Print the cumulative hash of applied transactions to stderr after processing
+    #[arg(long)]
+    ledger_hash: bool,
+
+    /// Print account, locked and open dispute counts and the summed account totals to
+    /// stderr after writing output
+    #[arg(long)]
+    stats: bool,
+
+    /// Print applied deposits, withdrawals and their net against the summed account
+    /// totals to stderr after writing output; any gap comes from chargebacks or
+    /// opening balances
+    #[arg(long)]
+    reconcile_summary: bool,
+
+    /// Only tally records per transaction type and print the breakdown; balances are not computed
+    #[arg(long)]
+    count_only: bool,
+
+    /// Only parse the input and print how many rows are valid and malformed; nothing is
+    /// processed or written, and any malformed row fails the run
+    #[arg(long, conflicts_with = "count_only")]
+    check: bool,
+
+    /// Flush output every N rows instead of only once at the end
+    #[arg(long, value_name = "N")]
+    flush_interval: Option<NonZeroUsize>,
+
+    /// Buffer up to BYTES of output before writing it out, on top of the CSV writer's
+    /// own buffer
+    #[arg(long, value_name = "BYTES")]
+    output_buffer: Option<NonZeroUsize>,
+
+    /// Which balances a withdrawal may draw from
+    #[arg(long, value_enum, default_value_t = WithdrawalPolicy::AvailableOnly)]
+    withdrawal_policy: WithdrawalPolicy,
+
+    /// What a chargeback without an amount does with funds that partial resolves of the
+    /// same dispute already released
+    #[arg(long, value_enum, default_value_t = ReleasedRemainderPolicy::Keep)]
+    released_remainder: ReleasedRemainderPolicy,
+
+    /// Apply only records of TYPE and skip the rest, e.g. to replay chargebacks from a
+    /// corrections file on top of --opening balances
+    #[arg(long, value_enum, value_name = "TYPE")]
+    only_type: Option<TransactionType>,
+
+    /// Reject deposits that would push an account's total above AMOUNT
+    #[arg(long, value_name = "AMOUNT")]
+    max_balance: Option<Decimal>,
+
+    /// Reject withdrawals that would leave available funds below AMOUNT
+    #[arg(long, value_name = "AMOUNT")]
+    min_balance: Option<Decimal>,
+
+    /// Abort without writing output if a withdrawal or chargeback drives the sum of all
+    /// account totals negative, a sign of corrupt input
+    #[arg(long)]
+    guard_system_total: bool,
+
+    /// Keep the stored transactions under roughly BYTES of memory by evicting the oldest
+    /// ones not under dispute; evicted transactions can no longer be disputed
+    #[arg(long, value_name = "BYTES")]
+    max_memory: Option<usize>,
+
+    /// Reject disputes while N transactions are already under dispute across all clients
+    #[arg(long, value_name = "N")]
+    max_system_disputes: Option<usize>,
+
+    /// Reject a client's disputes while N of its transactions are already under dispute
+    #[arg(long, value_name = "N")]
+    max_active_disputes: Option<usize>,
+
+    /// Whether a dispute may hold a deposit whose funds were partly withdrawn since,
+    /// driving available negative; with `false` such disputes are skipped with a warning
+    #[arg(long, value_name = "BOOL", default_value_t = true, action = ArgAction::Set)]
+    allow_negative_available: bool,
+
+    /// Write the account states to PATH instead of stdout
+    #[arg(long, value_name = "PATH")]
+    output: Option<String>,
+
+    /// Write account states into N files under DIR, `accounts-<i>.csv` holding the clients
+    /// with `client % N == i`, instead of to stdout. The files are always plain CSV
+    #[arg(
+        long,
+        value_name = "DIR",
+        requires = "partitions",
+        conflicts_with_all = [
+            "output",
+            "net",
+            "holds_over",
+            "baseline",
+            "format",
+            "with_metadata",
+            "with_schema_version",
+        ]
+    )]
+    partition_output: Option<PathBuf>,
+
+    /// Number of files for --partition-output
+    #[arg(long, value_name = "N", requires = "partition_output")]
+    partitions: Option<NonZeroU16>,
+
+    /// With --output, write the account states to stdout as well
+    #[arg(long, requires = "output")]
+    also_stdout: bool,
+
+    /// Report `client,gross_in,gross_out,net` instead of account balances
+    #[arg(long)]
+    net: bool,
+
+    /// Report only accounts whose held balance exceeds AMOUNT, largest held first
+    #[arg(long, value_name = "AMOUNT", conflicts_with = "net")]
+    holds_over: Option<Decimal>,
+
+    /// Report only accounts that differ from PATH, the output of an earlier run; accounts
+    /// missing from it count as changed
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["net", "holds_over"])]
+    baseline: Option<PathBuf>,
+
+    /// Serialization for account states and the --net report
+    #[arg(long, value_enum, default_value_t = OutputFormat::Csv)]
+    format: OutputFormat,
+
+    /// Write amounts with a comma as the decimal separator (`1234,56`)
+    #[arg(long)]
+    output_decimal_comma: bool,
+
+    /// Group thousands in output amounts (`1,234.56`, or `1.234,56` with --output-decimal-comma)
+    #[arg(long)]
+    group_thousands: bool,
+
+    /// Pad output amounts with trailing zeros to at least N decimal places; --precision is
+    /// raised to N if lower, so padding never drops a digit
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(0..=28))]
+    pad_scale: Option<u32>,
+
+    /// Round output amounts to N decimal places, midpoints away from zero (0 to 28)
+    #[arg(long, value_name = "N", default_value_t = AMOUNT_SCALE)]
+    precision: u32,
+
+    /// Write each account's amounts to the scale of its most precise deposit (`5.00` + `2.5` → `7.50`), capped at four places
+    #[arg(long)]
+    preserve_scale: bool,
+
+    /// Add a `tx_count` column: the number of transactions applied to each account
+    #[arg(long)]
+    verbose: bool,
+
+    /// CSV of `client,available,held,locked` starting balances loaded before the input;
+    /// prior output works as-is
+    #[arg(long, value_name = "PATH")]
+    opening: Option<String>,
+
+    /// CSV of `tx,outcome` rows (resolve or chargeback) applied after the main input
+    #[arg(long, value_name = "PATH")]
+    resolutions: Option<String>,
+
+    /// Write `client,locked_at` rows for locked accounts to PATH, where `locked_at` is the
+    /// 0-based index of the locking chargeback among processed records
+    #[arg(long, value_name = "PATH")]
+    lock_log: Option<String>,
+
+    /// Write `client,tx,amount,sequence_index` rows for every applied chargeback to PATH,
+    /// where `sequence_index` is the 0-based row of the chargeback in the input, counting
+    /// malformed and filtered rows but not headers
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<String>,
+
+    /// Write every applied transaction to PATH as `type,client,tx,amount` rows that can be
+    /// replayed as input
+    #[arg(long, value_name = "PATH")]
+    event_log: Option<String>,
+
+    /// Write the engine state to PATH as JSON once processing is done, for `summary`
+    #[arg(long, value_name = "PATH")]
+    save_snapshot: Option<String>,
+
+    /// Check that stored transactions and account holds agree before writing output
+    #[arg(long)]
+    verify_consistency: bool,
+
+    /// Stop reading after SECONDS, write the accounts processed so far, and exit with an error
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds)]
+    timeout: Option<Duration>,
+
+    /// Apply at most N records per second, sleeping between them, so a demo can watch
+    /// balances evolve
+    #[arg(long, value_name = "N")]
+    replay_rate: Option<NonZeroU32>,
+
+    /// Report deposits that are statistical outliers to stderr (they are still applied)
+    #[arg(long)]
+    detect_amount_anomalies: bool,
+
+    /// Standard deviations from the running mean beyond which a deposit is reported
+    #[arg(
+        long,
+        value_name = "K",
+        default_value_t = 3.0,
+        requires = "detect_amount_anomalies"
+    )]
+    anomaly_threshold: f64,
+
+    /// Report clients with more than N rows to stderr, a sign of concatenated datasets
+    /// with overlapping client IDs
+    #[arg(long, value_name = "N")]
+    max_client_transactions: Option<u64>,
+
+    /// Report clients whose total moves across a range wider than AMOUNT to stderr, a
+    /// sign of concatenated datasets with overlapping client IDs
+    #[arg(long, value_name = "AMOUNT")]
+    max_balance_swing: Option<Decimal>,
+
+    /// Memory-map INPUT_FILE rather than reading it through a buffer. The file must not be
+    /// truncated or rewritten during the run
+    #[cfg(feature = "mmap")]
+    #[arg(long, requires = "input_file")]
+    mmap: bool,
+
+    /// Append a `# run_at=...,input=...,records=...,version=...` comment line to the output,
+    /// or with --format json, wrap the rows as `{"accounts": [...], "metadata": {...}}`
+    #[arg(long)]
+    with_metadata: bool,
+
+    /// Start the output with a `# schema_version=N` comment line, or with --format json,
+    /// add a top-level `schema_version` field next to `accounts`
+    #[arg(long)]
+    with_schema_version: bool,
+
+    /// Write a zero-balance row for every client named in the input, including clients
+    /// none of whose rows could be applied
+    #[arg(long)]
+    include_empty_clients: bool,
+
+    /// Fail without writing output if any input row was skipped
+    #[arg(long, conflicts_with = "quiet")]
+    strict: bool,
+
+    /// Suppress warnings about individual skipped rows; the summary count is still
+    /// printed
+    #[arg(long)]
+    quiet: bool,
+
+    /// Print the number of rows read and skipped so far to stderr every ROWS rows,
+    /// e.g. 100000
+    #[arg(long, value_name = "ROWS")]
+    progress: Option<NonZeroU64>,
 }
 
-fn run() -> Result<(), EngineError> {
-    let cli = Cli::parse();
+/// Counters a run keeps while it reads its input, apart from the parsed arguments.
+#[derive(Default)]
+struct RunState {
+    /// Input rows read so far.
+    rows: Cell<u64>,
+    /// Input rows skipped so far, as malformed or rejected for bad data.
+    skipped: Cell<u64>,
+}
+
+impl RunState {
+    /// Count an input row about to be handled. Every `--progress` rows, first report how
+    /// many rows were read and skipped before it.
+    fn count_row(&self, cli: &Cli) {
+        let rows = self.rows.get();
+        if cli
+            .progress
+            .is_some_and(|every| rows > 0 && rows.is_multiple_of(every.get()))
+        {
+            eprintln!("progress: {rows} rows read, {} skipped", self.skipped.get());
+        }
+        self.rows.set(rows + 1);
+    }
+
+    /// Warn about an input row being dropped and count it towards the skip summary.
+    fn skip(&self, cli: &Cli, message: impl std::fmt::Display) {
+        self.skipped.set(self.skipped.get() + 1);
+        cli.warn(message);
+    }
+}
+
+/// Provenance recorded by `--with-metadata`.
+#[derive(Serialize)]
+struct RunMetadata {
+    /// Seconds since the Unix epoch when the output was written.
+    run_at: u64,
+    input: String,
+    records: u64,
+    version: &'static str,
+}
+
+impl std::fmt::Display for RunMetadata {
+    /// The CSV form: a trailing comment line of `key=value` pairs.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "# run_at={},input={},records={},version={}",
+            self.run_at, self.input, self.records, self.version
+        )
+    }
+}
 
-    let file = File::open(&cli.input_file)?;
+#[derive(Subcommand)]
+enum Command {
+    /// Process INPUT and write its canonical output to GOLDEN
+    Bless { input: PathBuf, golden: PathBuf },
+
+    /// Process INPUT and report rows whose canonical output differs from GOLDEN
+    Diff { input: PathBuf, golden: PathBuf },
+
+    /// Rewrite the well-formed rows of INPUT to stdout as trimmed
+    /// `type,client,tx,amount` CSV, without processing them
+    Normalize {
+        input: PathBuf,
+
+        /// Letter case of the `type` field
+        #[arg(long, value_enum, default_value_t)]
+        type_case: TypeCase,
+    },
+
+    /// Print account count, total balance, locked count and open disputes of a
+    /// snapshot written by --save-snapshot, without writing its accounts
+    Summary { snapshot: PathBuf },
+}
+
+impl Cli {
+    /// Name of the input being processed, as recorded in `--with-metadata`. Several
+    /// input files are listed in order, separated by `;`.
+    fn input_name(&self) -> String {
+        #[cfg(feature = "tar")]
+        if let Some(path) = &self.tar {
+            return path.clone();
+        }
+        if self.input_file.is_empty() {
+            return "-".to_owned();
+        }
+        self.input_file.join(";")
+    }
+
+    /// Print a warning to stderr unless `--quiet` was given.
+    fn warn(&self, message: impl std::fmt::Display) {
+        if !self.quiet {
+            eprintln!("warning: {message}");
+        }
+    }
+}
+
+/// Rewrite a decimal-comma `amount` field (`1.234,56`) into the `1234.56` form the
+/// parser expects. The row has already been split into fields, so the comma cannot be
+/// mistaken for a delimiter here. A `.` that cannot be a thousands separator, as in
+/// `5.00`, fails the row rather than being dropped, which would scale the amount.
+fn swap_decimal_comma(row: &mut StringRecord, amount_idx: usize) -> Result<(), String> {
+    let Some(field) = row.get(amount_idx) else {
+        return Ok(());
+    };
+    if !field.contains([',', '.']) {
+        return Ok(());
+    }
+
+    let integer = field.split(',').next().unwrap_or_default();
+    let mut groups = integer.split('.');
+    let leading = groups.next().unwrap_or_default();
+    let grouped = groups.all(|group| group.len() == 3);
+    if integer.contains('.') && (leading.trim_start_matches(['-', '+']).is_empty() || !grouped) {
+        return Err(format!(
+            "amount `{field}` whose `.` is not a thousands separator"
+        ));
+    }
+
+    let amount: String = field
+        .chars()
+        .filter(|&c| c != '.')
+        .map(|c| if c == ',' { '.' } else { c })
+        .collect();
+    let mut fields: Vec<String> = row.iter().map(str::to_owned).collect();
+    fields[amount_idx] = amount;
+    *row = StringRecord::from(fields);
+    Ok(())
+}
+
+/// Rewrite a fractional `amount` field (`a/b`) into its decimal form so the row
+/// deserializes like any other. Rows without a `/` in the amount pass through untouched.
+fn expand_fraction(row: &mut StringRecord, amount_idx: usize) -> Result<(), String> {
+    let Some(field) = row.get(amount_idx) else {
+        return Ok(());
+    };
+    if !field.contains('/') {
+        return Ok(());
+    }
+
+    let amount = parse_fraction(field).ok_or_else(|| format!("invalid fraction `{field}`"))?;
+    let mut fields: Vec<String> = row.iter().map(str::to_owned).collect();
+    fields[amount_idx] = amount.to_string();
+    *row = StringRecord::from(fields);
+    Ok(())
+}
+
+/// Read every well-formed record from the configured input, handing each to `on_record`.
+/// Malformed rows are logged to stderr and skipped, but an input in which no row at
+/// all parses fails with [`EngineError::MalformedRows`], as it is almost certainly
+/// not a transactions file.
+/// Returns [`EngineError::Timeout`] once `deadline` passes; records read up to that
+/// point have already been handed to `on_record`. An error from `on_record` stops
+/// reading at once and is returned as is.
+fn read_records(
+    cli: &Cli,
+    state: &RunState,
+    deadline: Option<Instant>,
+    mut on_record: impl FnMut(TransactionRecord) -> Result<(), EngineError>,
+) -> Result<(), EngineError> {
+    let mut parsed = 0u64;
+    read_sources(cli, state, deadline, |record| {
+        parsed += 1;
+        on_record(record)
+    })?;
+
+    let rows = state.rows.get();
+    if rows > 0 && parsed == 0 {
+        return Err(EngineError::MalformedRows { count: rows });
+    }
+    Ok(())
+}
+
+fn read_sources(
+    cli: &Cli,
+    state: &RunState,
+    deadline: Option<Instant>,
+    mut on_record: impl FnMut(TransactionRecord) -> Result<(), EngineError>,
+) -> Result<(), EngineError> {
+    #[cfg(feature = "tar")]
+    if let Some(path) = &cli.tar {
+        return read_tar(cli, state, path, deadline, &mut on_record);
+    }
+
+    if cli.input_file.is_empty() {
+        return read_stdin(cli, state, deadline, &mut on_record);
+    }
+
+    for path in &cli.input_file {
+        if path == "-" {
+            read_stdin(cli, state, deadline, &mut on_record)?;
+        } else {
+            read_file(cli, state, path, deadline, &mut on_record)?;
+        }
+    }
+    Ok(())
+}
+
+/// Feed stdin through [`read_csv`], decompressing it when `--gzip` was given; there is
+/// no file name to take the `.gz` extension from.
+fn read_stdin(
+    cli: &Cli,
+    state: &RunState,
+    deadline: Option<Instant>,
+    on_record: &mut impl FnMut(TransactionRecord) -> Result<(), EngineError>,
+) -> Result<(), EngineError> {
+    let stdin = std::io::stdin().lock();
+    if cli.gzip {
+        return read_csv(cli, state, GzDecoder::new(stdin), deadline, on_record);
+    }
+    read_csv(cli, state, stdin, deadline, on_record)
+}
+
+/// Feed one input file through [`read_csv`], decompressing it when it ends in `.gz`
+/// or `--gzip` was given.
+fn read_file(
+    cli: &Cli,
+    state: &RunState,
+    path: &str,
+    deadline: Option<Instant>,
+    on_record: &mut impl FnMut(TransactionRecord) -> Result<(), EngineError>,
+) -> Result<(), EngineError> {
+    let gzip = cli.gzip || Path::new(path).extension().is_some_and(|ext| ext == "gz");
+
+    #[cfg(feature = "mmap")]
+    if cli.mmap {
+        return read_mapped(cli, state, path, gzip, deadline, on_record);
+    }
+
+    let file = open_input(path)?;
+    if gzip {
+        return read_csv(cli, state, GzDecoder::new(file), deadline, on_record);
+    }
+    read_csv(cli, state, file, deadline, on_record)
+}
+
+fn open_input(path: &str) -> Result<File, EngineError> {
+    File::open(path).map_err(|source| EngineError::OpenInput {
+        path: path.to_owned(),
+        source,
+    })
+}
+
+/// Feed a memory-mapped input file through [`read_csv`], decompressing it first when
+/// `gzip` is set. Falls back to buffered reading when the file cannot be mapped, and
+/// fails if the file's length or modification time changed while it was mapped. The
+/// file must not be truncated while it is read; see the safety note below.
+#[cfg(feature = "mmap")]
+fn read_mapped(
+    cli: &Cli,
+    state: &RunState,
+    path: &str,
+    gzip: bool,
+    deadline: Option<Instant>,
+    on_record: &mut impl FnMut(TransactionRecord) -> Result<(), EngineError>,
+) -> Result<(), EngineError> {
+    let file = open_input(path)?;
+    let before = file.metadata()?;
+
+    // SAFETY: the mapping is read-only and only lives for this call, but nothing stops
+    // another process from modifying the file meanwhile. That is undefined behavior:
+    // truncation raises SIGBUS on the next access to a page past the new end, before
+    // any check here runs. The metadata check below only catches in-place changes that
+    // happened to leave the mapped pages readable.
+    let map = match unsafe { memmap2::Mmap::map(&file) } {
+        Ok(map) => map,
+        Err(e) => {
+            cli.warn(format_args!(
+                "cannot memory-map {path} ({e}); reading it instead"
+            ));
+            if gzip {
+                return read_csv(cli, state, GzDecoder::new(file), deadline, on_record);
+            }
+            return read_csv(cli, state, file, deadline, on_record);
+        }
+    };
+
+    if gzip {
+        read_csv(cli, state, GzDecoder::new(&map[..]), deadline, on_record)?;
+    } else {
+        read_csv(cli, state, &map[..], deadline, on_record)?;
+    }
+
+    let after = file.metadata()?;
+    if after.len() != before.len() || after.modified().ok() != before.modified().ok() {
+        return Err(EngineError::InputChanged(path.to_owned()));
+    }
+    Ok(())
+}
+
+/// Feed the `.csv` members of a gzipped tarball through [`read_csv`], sorted by
+/// member name so the processing order does not depend on how the archive was built.
+#[cfg(feature = "tar")]
+fn read_tar(
+    cli: &Cli,
+    state: &RunState,
+    path: &str,
+    deadline: Option<Instant>,
+    on_record: &mut impl FnMut(TransactionRecord) -> Result<(), EngineError>,
+) -> Result<(), EngineError> {
+    use std::io::Read;
+
+    let mut archive = tar::Archive::new(GzDecoder::new(open_input(path)?));
+    let mut members = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.into_owned();
+        if !entry.header().entry_type().is_file() || name.extension().is_none_or(|ext| ext != "csv")
+        {
+            continue;
+        }
+
+        let mut contents = Vec::new();
+        entry.read_to_end(&mut contents)?;
+        members.push((name, contents));
+    }
+
+    members.sort_by(|a, b| a.0.cmp(&b.0));
+    for (_, contents) in members {
+        read_csv(cli, state, contents.as_slice(), deadline, on_record)?;
+    }
+    Ok(())
+}
+
+fn read_csv<R: std::io::Read>(
+    cli: &Cli,
+    state: &RunState,
+    input: R,
+    deadline: Option<Instant>,
+    on_record: &mut impl FnMut(TransactionRecord) -> Result<(), EngineError>,
+) -> Result<(), EngineError> {
     let mut reader = ReaderBuilder::new()
         .trim(csv::Trim::All)
         .flexible(false)
-        .from_reader(file);
+        .delimiter(cli.delimiter)
+        .has_headers(!cli.no_headers)
+        .from_reader(input);
+
+    let headers = if cli.no_headers {
+        cli.field_order
+            .clone()
+            .unwrap_or_else(|| StringRecord::from(INPUT_COLUMNS.to_vec()))
+    } else {
+        reader.headers()?.clone()
+    };
+    // Headerless input always has one: the default column list and --field-order both
+    // name it
+    let amount_idx = headers.iter().position(|h| h == "amount");
+    if amount_idx.is_none() {
+        if cli.require_amount_column {
+            return Err(EngineError::MissingColumn("amount"));
+        }
+        cli.warn("input has no `amount` column, so its rows cannot be parsed");
+    }
 
+    for result in reader.records() {
+        // Checked on every row, so a slow input or several short files cannot slip past
+        // the deadline
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Err(EngineError::Timeout(cli.timeout.unwrap_or_default()));
+        }
+        state.count_row(cli);
+
+        let mut row = match result {
+            Ok(row) => row,
+            Err(e) => {
+                state.skip(cli, format_args!("skipping malformed row: {e}"));
+                continue;
+            }
+        };
+
+        if let (true, Some(amount_idx)) = (cli.decimal_comma, amount_idx) {
+            if let Err(e) = swap_decimal_comma(&mut row, amount_idx) {
+                state.skip(cli, format_args!("skipping row with {e}"));
+                continue;
+            }
+        }
+
+        if let (true, Some(amount_idx)) = (cli.allow_fractions, amount_idx) {
+            if let Err(e) = expand_fraction(&mut row, amount_idx) {
+                state.skip(cli, format_args!("skipping row with {e}"));
+                continue;
+            }
+        }
+
+        match row.deserialize::<TransactionRecord>(Some(&headers)) {
+            Ok(mut record) => {
+                let whole = record.amount.filter(|amount| amount.scale() == 0);
+                if let (true, Some(amount)) = (cli.require_fractional, whole) {
+                    state.skip(
+                        cli,
+                        format_args!(
+                            "skipping tx {}: amount {amount} has no fractional part",
+                            record.tx
+                        ),
+                    );
+                    continue;
+                }
+                // Amounts are parsed exactly, but output has always shown the
+                // shortest form (`20.0` as `20`); keep that unless asked for the
+                // input scale.
+                if !cli.preserve_scale {
+                    record.amount = record.amount.map(|amount| amount.normalize());
+                }
+                on_record(record)?;
+            }
+            Err(e) => {
+                state.skip(cli, format_args!("skipping malformed row: {e}"));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Run `record` through the engine, warning about rejections caused by bad data.
+/// Withdrawals the account cannot cover, or that would break the minimum balance, are
+/// valid rows, so they are reported without counting as skipped. Dispute-flow
+/// rejections are ignored silently, as the spec requires. A dispute row carrying an
+/// amount is applied, but warned about, since disputes always cover the whole
+/// transaction.
+fn apply(cli: &Cli, state: &RunState, engine: &mut PaymentsEngine, record: TransactionRecord) {
+    let (client, tx) = (record.client, record.tx);
+    if let (TransactionType::Dispute, Some(amount)) = (&record.r#type, record.amount) {
+        cli.warn(format_args!(
+            "dispute tx {tx} has amount {amount}, which is ignored"
+        ));
+    }
+    // The row being handled was just counted
+    let row = state.rows.get().saturating_sub(1);
+    match engine.process_row(record, row) {
+        Ok(()) => {}
+        Err(rejection) if rejection.warrants_warning() => {
+            state.skip(cli, format_args!("skipping tx {tx}: {rejection}"));
+        }
+        Err(
+            rejection @ (TransactionRejection::InsufficientFunds
+            | TransactionRejection::BelowMinimumBalance),
+        ) => {
+            cli.warn(format_args!(
+                "rejected withdrawal tx {tx} for client {client}: {rejection}"
+            ));
+        }
+        Err(_) => {}
+    }
+}
+
+/// Apply an external dispute outcome. Unlike a dispute-flow row in the input, a
+/// resolution is a deliberate decision, so every rejection is warned about and counted
+/// as skipped.
+fn apply_resolution(
+    cli: &Cli,
+    state: &RunState,
+    engine: &mut PaymentsEngine,
+    resolution: ResolutionRecord,
+) {
+    let tx = resolution.tx;
+    if let Err(rejection) = engine.apply_resolution(resolution) {
+        state.skip(
+            cli,
+            format_args!("skipping resolution of tx {tx}: {rejection}"),
+        );
+    }
+}
+
+/// Process `input` with default engine settings and render its account states with
+/// default output settings, the form that `bless` writes and `diff` compares against.
+fn canonical_output(cli: &Cli, input: &Path) -> Result<String, EngineError> {
+    // Subcommands take no top-level flags, so the engine always has default settings
     let mut engine = PaymentsEngine::new();
+    let state = RunState::default();
+    read_csv(cli, &state, File::open(input)?, None, &mut |record| {
+        apply(cli, &state, &mut engine, record);
+        Ok(())
+    })?;
+
+    let mut out = Vec::new();
+    engine.write_output(&mut out)?;
+    Ok(String::from_utf8(out).expect("CSV output is UTF-8"))
+}
+
+/// Write every row of `input` that parses as a transaction back out in canonical form,
+/// with the `type` field in `type_case`. Malformed rows are skipped with a warning.
+fn normalize(cli: &Cli, input: &Path, type_case: TypeCase) -> Result<(), EngineError> {
+    let mut wtr = csv::Writer::from_writer(std::io::stdout());
+    wtr.write_record(INPUT_COLUMNS)?;
+
+    let state = RunState::default();
+    read_csv(cli, &state, File::open(input)?, None, &mut |record| {
+        let amount = record.amount.map(|amount| amount.to_string());
+        wtr.write_record([
+            type_case.apply(&record.r#type).as_str(),
+            &record.client.to_string(),
+            &record.tx.to_string(),
+            amount.as_deref().unwrap_or_default(),
+        ])?;
+        Ok(())
+    })?;
+    wtr.flush()?;
+    Ok(())
+}
+
+/// Compare two rendered outputs row by row, keyed on the leading `client` field,
+/// printing each mismatch as `-golden` / `+actual` lines. Returns the number of
+/// differing rows.
+fn diff_outputs(out: &mut impl Write, golden: &str, actual: &str) -> std::io::Result<usize> {
+    fn by_client(output: &str) -> BTreeMap<&str, &str> {
+        output
+            .lines()
+            .map(|line| (line.split(',').next().unwrap_or_default(), line))
+            .collect()
+    }
+
+    let golden = by_client(golden);
+    let actual = by_client(actual);
+    let mut clients: Vec<&str> = golden.keys().chain(actual.keys()).copied().collect();
+    clients.sort_unstable();
+    clients.dedup();
+
+    let mut differences = 0;
+    for client in clients {
+        let (expected, got) = (golden.get(client), actual.get(client));
+        if expected == got {
+            continue;
+        }
+        differences += 1;
+        if let Some(line) = expected {
+            writeln!(out, "-{line}")?;
+        }
+        if let Some(line) = got {
+            writeln!(out, "+{line}")?;
+        }
+    }
+    Ok(differences)
+}
+
+/// Parse a `--delimiter`, which must be a single ASCII character.
+fn parse_delimiter(s: &str) -> Result<u8, EngineError> {
+    match s.as_bytes() {
+        [byte] if byte.is_ascii() => Ok(*byte),
+        _ => Err(EngineError::InvalidDelimiter(s.to_owned())),
+    }
+}
+
+/// Parse a `--field-order` list, which must name each input column exactly once.
+fn parse_field_order(s: &str) -> Result<StringRecord, String> {
+    let fields: StringRecord = s.split(',').map(str::trim).collect();
+    for column in INPUT_COLUMNS {
+        let count = fields.iter().filter(|field| *field == column).count();
+        if count != 1 {
+            return Err(format!("`{column}` must appear exactly once"));
+        }
+    }
+    if let Some(unknown) = fields.iter().find(|field| !INPUT_COLUMNS.contains(field)) {
+        return Err(format!("unknown column `{unknown}`"));
+    }
+    Ok(fields)
+}
+
+/// Parse a non-negative number of seconds, allowing fractions (`0.5`).
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    Duration::try_from_secs_f64(secs).map_err(|e| format!("{e}"))
+}
 
-    for result in reader.deserialize::<TransactionRecord>() {
+fn run() -> Result<(), EngineError> {
+    let cli = Cli::parse();
+    let state = RunState::default();
+    let deadline = cli.timeout.map(|timeout| Instant::now() + timeout);
+
+    match &cli.command {
+        Some(Command::Bless { input, golden }) => {
+            std::fs::write(golden, canonical_output(&cli, input)?)?;
+            return Ok(());
+        }
+        Some(Command::Diff { input, golden }) => {
+            let expected = std::fs::read_to_string(golden)?;
+            let actual = canonical_output(&cli, input)?;
+            let differences = diff_outputs(&mut std::io::stdout().lock(), &expected, &actual)?;
+            if differences > 0 {
+                return Err(EngineError::GoldenMismatch(differences));
+            }
+            return Ok(());
+        }
+        Some(Command::Normalize { input, type_case }) => {
+            return normalize(&cli, input, *type_case);
+        }
+        Some(Command::Summary { snapshot }) => {
+            let snapshot: EngineSnapshot =
+                serde_json::from_reader(BufReader::new(File::open(snapshot)?))?;
+            let mut wtr = csv::Writer::from_writer(std::io::stdout());
+            wtr.serialize(snapshot.summary()?)?;
+            wtr.flush()?;
+            return Ok(());
+        }
+        None => {}
+    }
+
+    if cli.count_only {
+        let mut counts = TypeCounts::default();
+        read_records(&cli, &state, deadline, |record| {
+            counts.add(&record.r#type);
+            Ok(())
+        })?;
+
+        let mut wtr = csv::Writer::from_writer(std::io::stdout());
+        wtr.write_record(["type", "count"])?;
+        for (r#type, count) in counts.iter() {
+            wtr.write_record([r#type.as_str(), &count.to_string()])?;
+        }
+        wtr.flush()?;
+        return Ok(());
+    }
+
+    if cli.check {
+        let mut valid = 0u64;
+        let read = read_records(&cli, &state, deadline, |_| {
+            valid += 1;
+            Ok(())
+        });
+        // An input without a single valid row is reported through the counts below
+        if !matches!(read, Err(EngineError::MalformedRows { .. })) {
+            read?;
+        }
+
+        let rows = state.rows.get();
+        let malformed = rows - valid;
+        let mut wtr = csv::Writer::from_writer(std::io::stdout());
+        wtr.write_record(["valid", "malformed"])?;
+        wtr.write_record([valid.to_string(), malformed.to_string()])?;
+        wtr.flush()?;
+
+        if malformed > 0 {
+            return Err(EngineError::InvalidRows { malformed, rows });
+        }
+        return Ok(());
+    }
+
+    let mut engine = PaymentsEngine::builder()
+        .output_options(OutputOptions {
+            output_format: cli.format,
+            flush_interval: cli.flush_interval,
+            decimal_comma: cli.output_decimal_comma,
+            group_thousands: cli.group_thousands,
+            pad_scale: cli.pad_scale,
+            verbose: cli.verbose,
+            preserve_scale: cli.preserve_scale,
+            precision: Some(cli.precision),
+        })
+        .withdrawal_policy(cli.withdrawal_policy)
+        .released_remainder(cli.released_remainder)
+        .max_balance(cli.max_balance)
+        .min_balance(cli.min_balance)
+        .max_open_disputes(cli.max_system_disputes)
+        .max_client_disputes(cli.max_active_disputes)
+        .allow_negative_available(cli.allow_negative_available)
+        .include_empty_clients(cli.include_empty_clients)
+        .integer_amounts(cli.integer_amounts)
+        .max_memory(cli.max_memory)
+        .event_log(cli.event_log.is_some())
+        .chargeback_log(cli.audit_log.is_some())
+        .build()?;
+
+    if let Some(path) = &cli.opening {
+        let mut reader = ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(false)
+            .from_reader(open_input(path)?);
+
+        for result in reader.deserialize::<OpeningBalance>() {
+            match result {
+                Ok(opening) => {
+                    if let Err(rejection) = engine.seed_account(&opening) {
+                        cli.warn(format_args!(
+                            "skipping opening balance for client {}: {rejection}",
+                            opening.client
+                        ));
+                    }
+                }
+                Err(e) => {
+                    cli.warn(format_args!("skipping malformed opening balance: {e}"));
+                }
+            }
+        }
+    }
+
+    let mut detector = cli
+        .detect_amount_anomalies
+        .then(|| AmountAnomalyDetector::new(cli.anomaly_threshold));
+    let mut activity = (cli.max_client_transactions.is_some() || cli.max_balance_swing.is_some())
+        .then(|| ClientActivityMonitor::new(cli.max_client_transactions, cli.max_balance_swing));
+    let total_of = |engine: &PaymentsEngine, client| {
+        engine
+            .account(client)
+            .map_or(Decimal::ZERO, |account| account.total)
+    };
+    // Kept up to date from per-client changes, as summing every account per row is too slow
+    let mut system_total = cli
+        .guard_system_total
+        .then(|| engine.stats().map(|stats| stats.total))
+        .transpose()?;
+    // Looking up the client's total around each row is only worth it for these two
+    let track_totals = activity.is_some() || system_total.is_some();
+    let replay_start = Instant::now();
+    let mut replayed = 0u64;
+    let read = read_records(&cli, &state, deadline, |record| {
+        if cli
+            .only_type
+            .as_ref()
+            .is_some_and(|only| *only != record.r#type)
+        {
+            return Ok(());
+        }
+        // Pace against the start time rather than the previous record, so sleeps that
+        // overshoot do not add up
+        if let Some(rate) = cli.replay_rate {
+            replayed += 1;
+            let due =
+                replay_start + Duration::from_secs_f64(replayed as f64 / f64::from(rate.get()));
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                std::thread::sleep(wait);
+            }
+        }
+        if let Some(anomaly) = detector.as_mut().and_then(|d| d.observe(&record)) {
+            eprintln!(
+                "anomaly: client {} tx {} deposit of {} is {:.1} standard deviations from the mean",
+                anomaly.client, anomaly.tx, anomaly.amount, anomaly.deviations
+            );
+        }
+        let (client, tx, r#type) = (record.client, record.tx, record.r#type.clone());
+        let before = track_totals.then(|| total_of(&engine, client));
+        apply(&cli, &state, &mut engine, record);
+        let Some(before) = before else {
+            return Ok(());
+        };
+        let after = total_of(&engine, client);
+        if let Some(activity) = activity.as_mut() {
+            activity.observe(client, before, after);
+        }
+        if let Some(total) = system_total.as_mut() {
+            *total = after
+                .checked_sub(before)
+                .and_then(|change| total.checked_add(change))
+                .ok_or(EngineError::TotalOverflow("account totals"))?;
+            if *total < Decimal::ZERO
+                && matches!(
+                    r#type,
+                    TransactionType::Withdrawal | TransactionType::Chargeback
+                )
+            {
+                return Err(EngineError::NegativeSystemTotal {
+                    tx,
+                    index: engine.records_processed() - 1,
+                    total: *total,
+                });
+            }
+        }
+        Ok(())
+    });
+
+    for anomaly in activity.iter().flat_map(|activity| activity.anomalies()) {
+        eprintln!(
+            "anomaly: client {} has {} rows and a balance swing of {}; the input may merge datasets with overlapping client IDs",
+            anomaly.client, anomaly.transactions, anomaly.swing
+        );
+    }
+
+    // On timeout, still flush the partial state below before reporting the error.
+    let timed_out = match read {
+        Err(EngineError::Timeout(limit)) => Some(limit),
+        other => {
+            other?;
+            None
+        }
+    };
+
+    if let (Some(path), None) = (&cli.resolutions, timed_out) {
+        let mut reader = ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(false)
+            .from_reader(File::open(path)?);
+
+        for result in reader.deserialize::<ResolutionRecord>() {
+            state.count_row(&cli);
+            match result {
+                Ok(resolution) => apply_resolution(&cli, &state, &mut engine, resolution),
+                Err(e) => {
+                    state.skip(&cli, format_args!("skipping malformed resolution: {e}"));
+                }
+            }
+        }
+    }
+
+    let (skipped, rows) = (state.skipped.get(), state.rows.get());
+    if skipped > 0 {
+        // Not silenced by --quiet: this is the only sign a non-strict run dropped rows
+        eprintln!("warning: skipped {skipped} of {rows} rows");
+        if cli.strict {
+            return Err(EngineError::SkippedRows { skipped, rows });
+        }
+    }
+
+    if cli.verify_consistency {
+        engine
+            .verify_consistency()
+            .map_err(EngineError::Inconsistent)?;
+    }
+
+    if let (Some(dir), Some(partitions)) = (&cli.partition_output, cli.partitions) {
+        write_partitions(&engine, dir, partitions)?;
+        return finish(&cli, &engine, timed_out);
+    }
+
+    let baseline = match &cli.baseline {
+        Some(path) => Some(read_baseline(&cli, path)?),
+        None => None,
+    };
+
+    let mut sinks: Vec<Box<dyn Write>> = Vec::new();
+    if let Some(path) = &cli.output {
+        sinks.push(buffered(&cli, File::create(path)?));
+    }
+    if cli.output.is_none() || cli.also_stdout {
+        sinks.push(buffered(&cli, std::io::stdout()));
+    }
+
+    let metadata = cli.with_metadata.then(|| RunMetadata {
+        run_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        input: cli.input_name(),
+        records: engine.records_processed(),
+        version: env!("CARGO_PKG_VERSION"),
+    });
+
+    if cli.format == OutputFormat::Json && (metadata.is_some() || cli.with_schema_version) {
+        // JSON has no comments, so the rows, metadata and schema version share a wrapper
+        // object.
+        let accounts = if cli.net {
+            serde_json::to_value(engine.net_records())?
+        } else if let Some(threshold) = cli.holds_over {
+            serde_json::to_value(engine.formatted_records(&engine.holds_over(threshold)))?
+        } else if let Some(baseline) = &baseline {
+            serde_json::to_value(engine.formatted_records(&engine.changed_since(baseline)))?
+        } else {
+            engine.to_json_value()
+        };
+        let mut wrapped = serde_json::json!({ "accounts": accounts });
+        if let Some(metadata) = &metadata {
+            wrapped["metadata"] = serde_json::to_value(metadata)?;
+        }
+        if cli.with_schema_version {
+            wrapped["schema_version"] = OUTPUT_SCHEMA_VERSION.into();
+        }
+        for sink in &mut sinks {
+            serde_json::to_writer(&mut *sink, &wrapped)?;
+            writeln!(sink)?;
+        }
+    } else {
+        if cli.with_schema_version {
+            for sink in &mut sinks {
+                writeln!(sink, "# schema_version={OUTPUT_SCHEMA_VERSION}")?;
+            }
+        }
+        if cli.net {
+            for sink in &mut sinks {
+                engine.write_net_output(sink)?;
+            }
+        } else if let Some(threshold) = cli.holds_over {
+            let records = engine.holds_over(threshold);
+            for sink in &mut sinks {
+                engine.write_records(&records, sink)?;
+            }
+        } else if let Some(baseline) = &baseline {
+            let records = engine.changed_since(baseline);
+            for sink in &mut sinks {
+                engine.write_records(&records, sink)?;
+            }
+        } else if let [sink] = sinks.as_mut_slice() {
+            engine.write_output(sink)?;
+        } else {
+            // Snapshot once so every sink receives identical rows.
+            let records = engine.output_records();
+            for sink in &mut sinks {
+                engine.write_records(&records, sink)?;
+            }
+        }
+
+        if let Some(metadata) = &metadata {
+            for sink in &mut sinks {
+                writeln!(sink, "{metadata}")?;
+            }
+        }
+    }
+
+    for sink in &mut sinks {
+        sink.flush()?;
+    }
+    finish(&cli, &engine, timed_out)
+}
+
+/// Wrap an output sink in a buffer of `--output-buffer` bytes, if one was asked for.
+fn buffered(cli: &Cli, sink: impl Write + 'static) -> Box<dyn Write> {
+    match cli.output_buffer {
+        Some(capacity) => Box::new(BufWriter::with_capacity(capacity.get(), sink)),
+        None => Box::new(sink),
+    }
+}
+
+/// Load the account states of an earlier run for `--baseline`, keyed by client.
+/// Malformed rows are skipped with a warning, so their accounts report as changed.
+fn read_baseline(cli: &Cli, path: &Path) -> Result<HashMap<u16, AccountSnapshot>, EngineError> {
+    let mut reader = ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .flexible(false)
+        .from_reader(File::open(path)?);
+
+    let mut baseline = HashMap::new();
+    for result in reader.deserialize::<BaselineRecord>() {
         match result {
-            Ok(record) => engine.process(record),
+            Ok(record) => {
+                baseline.insert(record.client, record.snapshot());
+            }
             Err(e) => {
-                eprintln!("warning: skipping malformed row: {e}");
+                cli.warn(format_args!("skipping malformed baseline row: {e}"));
             }
         }
     }
+    Ok(baseline)
+}
+
+/// Write the side outputs shared by every output mode, then report a timeout if one
+/// cut the input short.
+fn finish(
+    cli: &Cli,
+    engine: &PaymentsEngine,
+    timed_out: Option<Duration>,
+) -> Result<(), EngineError> {
+    if let Some(path) = &cli.lock_log {
+        engine.write_lock_log(File::create(path)?)?;
+    }
+
+    if let Some(path) = &cli.event_log {
+        engine.write_events(File::create(path)?)?;
+    }
 
-    engine.write_output(std::io::stdout())?;
+    if let Some(path) = &cli.audit_log {
+        engine.write_chargeback_log(File::create(path)?)?;
+    }
+
+    if let Some(path) = &cli.save_snapshot {
+        let mut file = BufWriter::new(File::create(path)?);
+        serde_json::to_writer(&mut file, &engine.to_snapshot())?;
+        file.flush()?;
+    }
+
+    if cli.ledger_hash {
+        let hex: String = engine
+            .ledger_hash()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect();
+        eprintln!("ledger hash: {hex}");
+    }
 
+    if engine.evicted() > 0 {
+        eprintln!(
+            "evicted {} stored transactions to stay under --max-memory",
+            engine.evicted()
+        );
+    }
+
+    if cli.stats {
+        let stats = engine.stats()?;
+        eprintln!(
+            "stats: accounts={} locked={} total={} open_disputes={}",
+            stats.accounts, stats.locked, stats.total, stats.open_disputes
+        );
+    }
+
+    if cli.reconcile_summary {
+        let summary = engine.reconciliation()?;
+        eprintln!(
+            "reconcile: deposits={} withdrawals={} net={} total={} difference={}",
+            summary.deposits.normalize(),
+            summary.withdrawals.normalize(),
+            summary.net.normalize(),
+            summary.total.normalize(),
+            summary.difference.normalize()
+        );
+    }
+
+    match timed_out {
+        Some(limit) => Err(EngineError::Timeout(limit)),
+        None => Ok(()),
+    }
+}
+
+/// Write account states into `partitions` files under `dir`, each sorted by client.
+/// Every file is created, even if no client falls into it, so the set of files does
+/// not depend on the input.
+fn write_partitions(
+    engine: &PaymentsEngine,
+    dir: &Path,
+    partitions: NonZeroU16,
+) -> Result<(), EngineError> {
+    std::fs::create_dir_all(dir)?;
+
+    let mut parts: Vec<Vec<OutputRecord>> = (0..partitions.get()).map(|_| Vec::new()).collect();
+    for record in engine.output_records() {
+        parts[usize::from(record.client % partitions.get())].push(record);
+    }
+
+    for (idx, records) in parts.iter().enumerate() {
+        let file = File::create(dir.join(format!("accounts-{idx}.csv")))?;
+        engine.write_records(records, file)?;
+    }
     Ok(())
 }
 
 fn main() {
     if let Err(e) = run() {
+        if e.is_broken_pipe() {
+            return;
+        }
         eprintln!("error: {e}");
         process::exit(1);
     }
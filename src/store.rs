@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+
+use crate::types::StoredTransaction;
+
+/// Where the engine keeps deposits and withdrawals for later dispute lookups.
+///
+/// The default is an in-memory `HashMap`. Implement this to keep the stored
+/// transactions elsewhere, e.g. in an external key-value store for inputs too large to
+/// hold in memory, and pass it to [`PaymentsEngine::with_store`](crate::PaymentsEngine::with_store).
+/// Entries are returned by value, so a store does not have to keep them in memory.
+pub trait TxStore {
+    /// The transaction stored under `tx`, if any.
+    fn get(&self, tx: u32) -> Option<StoredTransaction>;
+
+    /// Store `stored` under `tx`. The engine never inserts a `tx` that is already stored.
+    fn insert(&mut self, tx: u32, stored: StoredTransaction);
+
+    /// Mark the transaction stored under `tx` as under dispute or not. Does nothing if
+    /// `tx` is not stored.
+    fn set_disputed(&mut self, tx: u32, under_dispute: bool);
+
+    /// Drop the transaction stored under `tx`, if any. Only called to stay under a
+    /// [memory limit](crate::PaymentsEngine::with_max_memory).
+    fn remove(&mut self, tx: u32);
+
+    /// Whether anything is stored under `tx`.
+    fn contains(&self, tx: u32) -> bool {
+        self.get(tx).is_some()
+    }
+}
+
+impl TxStore for HashMap<u32, StoredTransaction> {
+    fn get(&self, tx: u32) -> Option<StoredTransaction> {
+        HashMap::get(self, &tx).cloned()
+    }
+
+    fn insert(&mut self, tx: u32, stored: StoredTransaction) {
+        HashMap::insert(self, tx, stored);
+    }
+
+    fn set_disputed(&mut self, tx: u32, under_dispute: bool) {
+        if let Some(stored) = self.get_mut(&tx) {
+            stored.under_dispute = under_dispute;
+        }
+    }
+
+    fn remove(&mut self, tx: u32) {
+        HashMap::remove(self, &tx);
+    }
+
+    fn contains(&self, tx: u32) -> bool {
+        self.contains_key(&tx)
+    }
+}
@@ -1,7 +1,23 @@
-use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
 
-#[derive(Debug, Clone, Deserialize, PartialEq)]
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::error::{EngineError, TransactionRejection};
+
+/// Number of decimal places the spec requires amounts to be precise to.
+pub const AMOUNT_SCALE: u32 = 4;
+
+/// Version of the account output layout, declared by `--with-schema-version`. Bumped
+/// whenever output columns (or JSON fields) are added, removed, renamed or reordered,
+/// or their meaning changes, so consumers can tell which layout they are reading.
+pub const OUTPUT_SCHEMA_VERSION: u32 = 1;
+
+/// Deserialized case-insensitively and ignoring surrounding whitespace (`Deposit`,
+/// ` DEPOSIT `); always serialized in lowercase.
+#[derive(Debug, Clone, Serialize, PartialEq, clap::ValueEnum)]
 #[serde(rename_all = "lowercase")]
 pub enum TransactionType {
     Deposit,
@@ -11,6 +27,114 @@ pub enum TransactionType {
     Chargeback,
 }
 
+impl TransactionType {
+    /// Lowercase name as it appears in the input `type` column.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        const NAMES: &[&str] = &["deposit", "withdrawal", "dispute", "resolve", "chargeback"];
+
+        let raw = String::deserialize(deserializer)?;
+        let name = raw.trim();
+        <Self as clap::ValueEnum>::value_variants()
+            .iter()
+            .find(|r#type| r#type.as_str().eq_ignore_ascii_case(name))
+            .cloned()
+            .ok_or_else(|| serde::de::Error::unknown_variant(name, NAMES))
+    }
+}
+
+/// Letter case for the `type` field when transactions are re-emitted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum TypeCase {
+    /// `deposit`, as the input spec writes it.
+    #[default]
+    Lowercase,
+    /// `DEPOSIT`
+    Uppercase,
+    /// `Deposit`
+    Capitalized,
+}
+
+impl TypeCase {
+    pub fn apply(self, r#type: &TransactionType) -> String {
+        let name = r#type.as_str();
+        match self {
+            TypeCase::Lowercase => name.to_owned(),
+            TypeCase::Uppercase => name.to_ascii_uppercase(),
+            TypeCase::Capitalized => name[..1].to_ascii_uppercase() + &name[1..],
+        }
+    }
+}
+
+/// Number of records seen per transaction type, used by `--count-only`.
+#[derive(Debug, Default)]
+pub struct TypeCounts {
+    pub deposit: u64,
+    pub withdrawal: u64,
+    pub dispute: u64,
+    pub resolve: u64,
+    pub chargeback: u64,
+}
+
+impl TypeCounts {
+    pub fn add(&mut self, r#type: &TransactionType) {
+        match r#type {
+            TransactionType::Deposit => self.deposit += 1,
+            TransactionType::Withdrawal => self.withdrawal += 1,
+            TransactionType::Dispute => self.dispute += 1,
+            TransactionType::Resolve => self.resolve += 1,
+            TransactionType::Chargeback => self.chargeback += 1,
+        }
+    }
+
+    /// Counts in input-spec order: deposit, withdrawal, dispute, resolve, chargeback.
+    pub fn iter(&self) -> impl Iterator<Item = (TransactionType, u64)> {
+        [
+            (TransactionType::Deposit, self.deposit),
+            (TransactionType::Withdrawal, self.withdrawal),
+            (TransactionType::Dispute, self.dispute),
+            (TransactionType::Resolve, self.resolve),
+            (TransactionType::Chargeback, self.chargeback),
+        ]
+        .into_iter()
+    }
+}
+
+/// Which balances a withdrawal may draw from.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum WithdrawalPolicy {
+    /// Only `available` funds can be withdrawn; `held` is never touched. This is the
+    /// spec's behavior.
+    #[default]
+    AvailableOnly,
+    /// Draw from `available` first and cover any shortfall from the holds of disputed
+    /// deposits, lowest tx first.
+    AvailableThenHeld,
+}
+
+/// What a full chargeback does with the part of a disputed transaction that partial
+/// resolves already released.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ReleasedRemainderPolicy {
+    /// Only what is still held is reversed; released funds stay with the client.
+    #[default]
+    Keep,
+    /// The released part is reversed too, so the whole transaction is charged back: a
+    /// deposit's released funds leave available, a withdrawal's are credited back.
+    Reverse,
+}
+
 /// `amount` is optional because dispute/resolve/chargeback rows
 /// do not carry an amount, they reference an existing transaction by tx ID.
 #[derive(Debug, Clone, Deserialize)]
@@ -18,22 +142,209 @@ pub struct TransactionRecord {
     pub r#type: TransactionType,
     pub client: u16,
     pub tx: u32,
+    #[serde(deserialize_with = "deserialize_exact_amount")]
     pub amount: Option<Decimal>,
 }
 
-#[derive(Debug, Clone)]
+/// Parse an amount from its text so the scale written in the input survives
+/// (`5.00` stays `5.00`); going through a float would drop it and any digits beyond
+/// `f64` precision. An empty field is no amount.
+fn deserialize_exact_amount<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Decimal>, D::Error> {
+    let Some(value) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    let value = value.trim();
+    if value.is_empty() {
+        return Ok(None);
+    }
+    Decimal::from_str(value)
+        .or_else(|_| Decimal::from_scientific(value))
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// A transaction the engine applied, recorded in the same `type,client,tx,amount` shape
+/// as the input. Disputes never carry an amount, and resolves and chargebacks only
+/// when partial, so a log of events can be replayed into a fresh engine, or fed back
+/// to the CLI, to rebuild the same state.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub struct LedgerEvent {
+    pub r#type: TransactionType,
+    pub client: u16,
+    pub tx: u32,
+    #[serde(deserialize_with = "deserialize_exact_amount")]
+    pub amount: Option<Decimal>,
+}
+
+impl LedgerEvent {
+    pub fn from_applied(record: &TransactionRecord) -> Self {
+        let amount = match record.r#type {
+            TransactionType::Dispute => None,
+            _ => record.amount,
+        };
+        Self {
+            r#type: record.r#type.clone(),
+            client: record.client,
+            tx: record.tx,
+            amount,
+        }
+    }
+}
+
+impl From<LedgerEvent> for TransactionRecord {
+    fn from(event: LedgerEvent) -> Self {
+        Self {
+            r#type: event.r#type,
+            client: event.client,
+            tx: event.tx,
+            amount: event.amount,
+        }
+    }
+}
+
+/// Parse an amount written as a fraction (`1/4`) into a `Decimal` rounded to
+/// [`AMOUNT_SCALE`] places. Returns `None` for anything that is not a valid
+/// `a/b` pair, including a zero denominator.
+pub fn parse_fraction(s: &str) -> Option<Decimal> {
+    let (numerator, denominator) = s.split_once('/')?;
+    let numerator: Decimal = numerator.trim().parse().ok()?;
+    let denominator: Decimal = denominator.trim().parse().ok()?;
+
+    numerator
+        .checked_div(denominator)
+        .map(|d| d.round_dp_with_strategy(AMOUNT_SCALE, RoundingStrategy::MidpointAwayFromZero))
+}
+
+/// Authoritative dispute outcome supplied by an external system via `--resolutions`.
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ResolutionOutcome {
+    Resolve,
+    Chargeback,
+}
+
+/// A `tx,outcome` row from a resolutions file. The owning client is taken from
+/// the stored transaction, so it does not need to be repeated here.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ResolutionRecord {
+    pub tx: u32,
+    pub outcome: ResolutionOutcome,
+}
+
+/// A starting balance loaded via `--opening`. Prior engine output can be fed back in
+/// as-is: `total` is recomputed from `available + held`, so that column is ignored.
+#[derive(Debug, Clone, Deserialize)]
+pub struct OpeningBalance {
+    pub client: u16,
+    pub available: Decimal,
+    pub held: Decimal,
+    #[serde(deserialize_with = "deserialize_lenient_bool")]
+    pub locked: bool,
+}
+
+/// An account row from the output of an earlier run, loaded via `--baseline`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct BaselineRecord {
+    pub client: u16,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    #[serde(deserialize_with = "deserialize_lenient_bool")]
+    pub locked: bool,
+}
+
+impl BaselineRecord {
+    pub fn snapshot(&self) -> AccountSnapshot {
+        AccountSnapshot {
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            locked: self.locked,
+        }
+    }
+}
+
+/// Accept the boolean spellings other tools emit: `true`/`false`, `1`/`0` and
+/// `yes`/`no`, in any case.
+fn deserialize_lenient_bool<'de, D: Deserializer<'de>>(deserializer: D) -> Result<bool, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    match value.trim().to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" => Ok(true),
+        "false" | "0" | "no" => Ok(false),
+        _ => Err(serde::de::Error::invalid_value(
+            serde::de::Unexpected::Str(&value),
+            &"true/false, 1/0 or yes/no",
+        )),
+    }
+}
+
+/// Which kind of transaction a stored entry came from. Disputes move funds in
+/// opposite directions for the two kinds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum StoredKind {
+    Deposit,
+    Withdrawal,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct StoredTransaction {
     pub client: u16,
     pub amount: Decimal,
+    pub kind: StoredKind,
     pub under_dispute: bool,
 }
 
-#[derive(Debug, Clone)]
+/// Strip trailing zeros once a balance carries more than [`AMOUNT_SCALE`] places, so
+/// repeated dispute/resolve cycles cannot grow its representation. The value is unchanged.
+fn bound_scale(value: Decimal) -> Decimal {
+    if value.scale() > AMOUNT_SCALE {
+        value.normalize()
+    } else {
+        value
+    }
+}
+
+/// `a + b`, rejecting the transaction instead of panicking when the result overflows.
+fn add(a: Decimal, b: Decimal) -> Result<Decimal, TransactionRejection> {
+    a.checked_add(b).ok_or(TransactionRejection::Overflow)
+}
+
+/// `a - b`, rejecting the transaction instead of panicking when the result overflows.
+fn sub(a: Decimal, b: Decimal) -> Result<Decimal, TransactionRejection> {
+    a.checked_sub(b).ok_or(TransactionRejection::Overflow)
+}
+
+/// `holds` records the amount held per disputed tx. `held` is their sum, plus any held
+/// balance carried over from `--opening`. `released` records how much of each still
+/// open dispute partial resolves have released.
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct ClientAccount {
     pub available: Decimal,
     pub held: Decimal,
     pub total: Decimal,
     pub locked: bool,
+    pub holds: HashMap<u32, Decimal>,
+    #[serde(default)]
+    pub released: HashMap<u32, Decimal>,
+    /// Index of the processed record whose chargeback locked this account.
+    pub locked_at: Option<u64>,
+    /// Cumulative deposits credited to the account.
+    pub gross_in: Decimal,
+    /// Cumulative withdrawals debited from the account.
+    pub gross_out: Decimal,
+    /// Number of transactions successfully applied to the account.
+    pub tx_count: u64,
+    /// Largest number of decimal places among credited deposits, capped at
+    /// [`AMOUNT_SCALE`].
+    pub max_scale: u32,
+}
+
+impl Default for ClientAccount {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ClientAccount {
@@ -43,46 +354,381 @@ impl ClientAccount {
             held: Decimal::ZERO,
             total: Decimal::ZERO,
             locked: false,
+            holds: HashMap::new(),
+            released: HashMap::new(),
+            locked_at: None,
+            gross_in: Decimal::ZERO,
+            gross_out: Decimal::ZERO,
+            tx_count: 0,
+            max_scale: 0,
         }
     }
 
-    pub fn deposit(&mut self, amount: Decimal) {
-        self.available += amount;
-        self.total += amount;
+    /// An account starting from an opening balance rather than zero. Fails if the
+    /// balances sum to a total too large to represent.
+    pub fn opening(opening: &OpeningBalance) -> Result<Self, TransactionRejection> {
+        Ok(Self {
+            available: opening.available,
+            held: opening.held,
+            total: add(opening.available, opening.held)?,
+            locked: opening.locked,
+            ..Self::new()
+        })
     }
 
-    pub fn withdraw(&mut self, amount: Decimal) -> bool {
-        if self.available >= amount {
-            self.available -= amount;
-            self.total -= amount;
-            true
-        } else {
-            false
+    pub fn deposit(&mut self, amount: Decimal) -> Result<(), TransactionRejection> {
+        let available = add(self.available, amount)?;
+        let total = add(self.total, amount)?;
+
+        self.available = available;
+        self.total = total;
+        self.gross_in = self.gross_in.saturating_add(amount);
+        self.max_scale = self.max_scale.max(amount.scale().min(AMOUNT_SCALE));
+        Ok(())
+    }
+
+    /// Debit `amount` as `policy` allows. Under
+    /// [`AvailableThenHeld`](WithdrawalPolicy::AvailableThenHeld), a shortfall is drawn
+    /// from the holds on `drawable`, the disputed deposits of this account in ascending
+    /// order. Other held funds, such as a disputed withdrawal's provisional credit or a
+    /// held opening balance, are not withdrawable.
+    pub fn withdraw(
+        &mut self,
+        amount: Decimal,
+        policy: WithdrawalPolicy,
+        drawable: &[u32],
+    ) -> Result<(), TransactionRejection> {
+        let total = sub(self.total, amount)?;
+        match policy {
+            WithdrawalPolicy::AvailableOnly => {
+                if self.available < amount {
+                    return Err(TransactionRejection::InsufficientFunds);
+                }
+                self.available -= amount;
+            }
+            WithdrawalPolicy::AvailableThenHeld => {
+                let from_available = self.available.max(Decimal::ZERO).min(amount);
+                let from_holds: Decimal = drawable.iter().filter_map(|tx| self.holds.get(tx)).sum();
+                if from_holds < amount - from_available {
+                    return Err(TransactionRejection::InsufficientFunds);
+                }
+                self.available -= from_available;
+                self.draw_from_holds(drawable, amount - from_available);
+            }
+        }
+        self.total = total;
+        self.gross_out = self.gross_out.saturating_add(amount);
+        Ok(())
+    }
+
+    /// Take `amount` out of the holds on `txs`, in the given order. Emptied holds stay
+    /// recorded at zero so their disputes can still be resolved or charged back.
+    fn draw_from_holds(&mut self, txs: &[u32], mut amount: Decimal) {
+        for tx in txs {
+            if amount.is_zero() {
+                break;
+            }
+            let Some(hold) = self.holds.get_mut(tx) else {
+                continue;
+            };
+            let taken = (*hold).min(amount);
+            *hold -= taken;
+            self.held -= taken;
+            amount -= taken;
+        }
+    }
+
+    /// Hold `amount` while `tx` is disputed. A disputed deposit moves the amount from
+    /// available to held. A disputed withdrawal has already left the account, so the
+    /// amount is held as a provisional credit, raising held and total.
+    pub fn hold(
+        &mut self,
+        tx: u32,
+        amount: Decimal,
+        kind: StoredKind,
+    ) -> Result<(), TransactionRejection> {
+        let held = add(self.held, amount)?;
+        match kind {
+            StoredKind::Deposit => self.available = bound_scale(sub(self.available, amount)?),
+            StoredKind::Withdrawal => self.total = bound_scale(add(self.total, amount)?),
         }
+        self.held = bound_scale(held);
+        *self.holds.entry(tx).or_default() += amount;
+        Ok(())
     }
 
-    pub fn hold(&mut self, amount: Decimal) {
-        self.available -= amount;
-        self.held += amount;
+    /// Whether `held` covers the hold recorded for `tx`, so releasing it cannot make
+    /// `held` negative.
+    pub fn holds_enough_for(&self, tx: u32) -> bool {
+        self.holds
+            .get(&tx)
+            .is_none_or(|&amount| self.held >= amount)
     }
 
-    pub fn release(&mut self, amount: Decimal) {
-        self.held -= amount;
-        self.available += amount;
+    /// Release `amount` of the hold on `tx` without reversing it: a deposit's funds
+    /// return to available, while a withdrawal's provisional credit is dropped and it
+    /// stands. The hold is removed once nothing of it remains.
+    pub fn release(
+        &mut self,
+        tx: u32,
+        kind: StoredKind,
+        amount: Decimal,
+    ) -> Result<(), TransactionRejection> {
+        let held = sub(self.held, amount)?;
+        match kind {
+            StoredKind::Deposit => self.available = bound_scale(add(self.available, amount)?),
+            StoredKind::Withdrawal => self.total = bound_scale(sub(self.total, amount)?),
+        }
+        self.held = bound_scale(held);
+        if let Some(hold) = self.holds.get_mut(&tx) {
+            *hold -= amount;
+            if hold.is_zero() {
+                self.holds.remove(&tx);
+                self.released.remove(&tx);
+            }
+        }
+        Ok(())
     }
 
-    pub fn chargeback(&mut self, amount: Decimal) {
-        self.held -= amount;
-        self.total -= amount;
+    /// Reverse `amount` of the hold on `tx` and lock the account: that part of a deposit
+    /// leaves the account, while that part of a withdrawal's credit is returned to
+    /// available. Whatever remains of the hold is released as in [`release`](Self::release),
+    /// so the dispute is over either way. `reclaimed`, part of the transaction released
+    /// earlier, is reversed as well, out of or into available.
+    pub fn chargeback(
+        &mut self,
+        tx: u32,
+        kind: StoredKind,
+        amount: Decimal,
+        reclaimed: Decimal,
+    ) -> Result<(), TransactionRejection> {
+        let hold = self.holds.get(&tx).copied().unwrap_or_default();
+        let rest = sub(hold, amount)?;
+        let held = sub(self.held, hold)?;
+        let (available, total) = match kind {
+            StoredKind::Deposit => (
+                sub(add(self.available, rest)?, reclaimed)?,
+                sub(self.total, add(amount, reclaimed)?)?,
+            ),
+            StoredKind::Withdrawal => (
+                add(self.available, add(amount, reclaimed)?)?,
+                add(sub(self.total, rest)?, reclaimed)?,
+            ),
+        };
+
+        self.held = held;
+        self.available = available;
+        self.total = total;
+        self.holds.remove(&tx);
+        self.released.remove(&tx);
         self.locked = true;
+        Ok(())
     }
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, PartialEq, Serialize)]
 pub struct OutputRecord {
     pub client: u16,
     pub available: Decimal,
     pub held: Decimal,
     pub total: Decimal,
     pub locked: bool,
+    pub tx_count: u64,
+    /// Largest number of decimal places among the client's deposits, capped at
+    /// [`AMOUNT_SCALE`]; used by [`OutputOptions::preserve_scale`].
+    #[serde(skip)]
+    pub scale: u32,
+}
+
+/// Point-in-time copy of an account's balances, returned by
+/// [`PaymentsEngine::account`](crate::PaymentsEngine::account).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AccountSnapshot {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+}
+
+/// Row of the `--net` report: gross flows and their difference.
+#[derive(Debug, Serialize)]
+pub struct NetRecord {
+    pub client: u16,
+    pub gross_in: String,
+    pub gross_out: String,
+    pub net: String,
+}
+
+/// Row of the `--lock-log` side file.
+#[derive(Debug, Serialize)]
+pub struct LockRecord {
+    pub client: u16,
+    pub locked_at: u64,
+}
+
+/// An applied chargeback, as a row of the `--audit-log` side file. `sequence_index` is
+/// the 0-based row of the chargeback in the input: the row given to
+/// [`PaymentsEngine::process_row`](crate::engine::PaymentsEngine::process_row), or else
+/// the chargeback's index among the records the engine processed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ChargebackEvent {
+    pub client: u16,
+    pub tx: u32,
+    pub amount: Decimal,
+    pub sequence_index: u64,
+}
+
+/// An [`OutputRecord`] with its amounts rendered as text by [`OutputOptions`].
+#[derive(Debug, Serialize)]
+pub struct FormattedRecord {
+    pub client: u16,
+    pub available: String,
+    pub held: String,
+    pub total: String,
+    pub locked: bool,
+    /// Only written with [`OutputOptions::verbose`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx_count: Option<u64>,
+}
+
+/// Serialization used for account states and the `--net` report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// A JSON array of row objects. Amounts are strings, so no precision is lost.
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = EngineError;
+
+    /// Parse a format name as accepted by `--format`, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(EngineError::UnknownFormat(s.to_owned())),
+        }
+    }
+}
+
+/// Settings that control how account states are written out.
+#[derive(Debug, Clone, Default)]
+pub struct OutputOptions {
+    pub output_format: OutputFormat,
+    /// Flush the writer after every `n` rows instead of only once at the end.
+    pub flush_interval: Option<NonZeroUsize>,
+    /// Use `,` as the decimal separator (`1234,56`).
+    pub decimal_comma: bool,
+    /// Group integer digits in threes, using `.` with `decimal_comma` and `,` otherwise.
+    pub group_thousands: bool,
+    /// Pad amounts with trailing zeros up to this many decimal places. Amounts are
+    /// rounded to at least this many places, so padding never drops a digit.
+    pub pad_scale: Option<u32>,
+    /// Add a `tx_count` column with the number of transactions applied per account.
+    pub verbose: bool,
+    /// Write each account's amounts with at least as many decimal places as the most
+    /// precise deposit it received (`5.00` + `2.5` gives `7.50`), instead of whatever
+    /// scale the arithmetic happened to leave.
+    pub preserve_scale: bool,
+    /// Decimal places amounts are rounded to; [`AMOUNT_SCALE`] when unset, and never
+    /// fewer than `pad_scale`. Set it through [`with_precision`](Self::with_precision)
+    /// to have it validated.
+    pub precision: Option<u32>,
+}
+
+impl OutputOptions {
+    /// Round emitted amounts to `precision` places instead of [`AMOUNT_SCALE`]. Fails
+    /// for more places than a `Decimal` can carry.
+    pub fn with_precision(mut self, precision: u32) -> Result<Self, EngineError> {
+        if precision > Decimal::MAX_SCALE {
+            return Err(EngineError::InvalidPrecision(precision));
+        }
+        self.precision = Some(precision);
+        Ok(self)
+    }
+
+    fn precision(&self) -> u32 {
+        let precision = self.precision.unwrap_or(AMOUNT_SCALE);
+        self.pad_scale
+            .map_or(precision, |scale| precision.max(scale))
+    }
+
+    /// Midpoints round away from zero (`0.125` → `0.13` at two places), the way
+    /// amounts are rounded by hand, rather than to even.
+    pub(crate) fn round(&self, amount: Decimal) -> Decimal {
+        amount.round_dp_with_strategy(self.precision(), RoundingStrategy::MidpointAwayFromZero)
+    }
+
+    pub fn format(&self, record: &OutputRecord) -> FormattedRecord {
+        FormattedRecord {
+            client: record.client,
+            available: self.format_record_amount(record, record.available),
+            held: self.format_record_amount(record, record.held),
+            total: self.format_record_amount(record, record.total),
+            locked: record.locked,
+            tx_count: self.verbose.then_some(record.tx_count),
+        }
+    }
+
+    /// Like [`format_amount`](Self::format_amount), but with `preserve_scale` the amount
+    /// is first brought to the record's observed scale: trailing zeros left by
+    /// arithmetic are dropped and those the client sent are restored. Places that
+    /// carry value are never cut.
+    fn format_record_amount(&self, record: &OutputRecord, amount: Decimal) -> String {
+        if !self.preserve_scale {
+            return self.format_amount(amount);
+        }
+        let scale = record.scale.min(self.precision());
+        let mut amount = self.round(amount).normalize();
+        if amount.scale() < scale {
+            amount.rescale(scale);
+        }
+        self.format_amount(amount)
+    }
+
+    /// Render an amount for output. Amounts are first rounded to the configured
+    /// precision (midpoints away from zero), raised to `pad_scale` if that is larger,
+    /// then padded and separated as configured.
+    pub fn format_amount(&self, amount: Decimal) -> String {
+        let mut amount = self.round(amount);
+        if let Some(scale) = self.pad_scale {
+            if amount.scale() < scale {
+                amount.rescale(scale);
+            }
+        }
+
+        let text = amount.to_string();
+        if !self.decimal_comma && !self.group_thousands {
+            return text;
+        }
+
+        let (sign, unsigned) = match text.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", text.as_str()),
+        };
+        let (integer, fraction) = match unsigned.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (unsigned, None),
+        };
+        let (decimal_sep, group_sep) = if self.decimal_comma {
+            (',', '.')
+        } else {
+            ('.', ',')
+        };
+
+        let mut out = String::from(sign);
+        for (i, digit) in integer.chars().enumerate() {
+            if self.group_thousands && i > 0 && (integer.len() - i) % 3 == 0 {
+                out.push(group_sep);
+            }
+            out.push(digit);
+        }
+        if let Some(fraction) = fraction {
+            out.push(decimal_sep);
+            out.push_str(fraction);
+        }
+        out
+    }
 }
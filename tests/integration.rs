@@ -9,201 +9,42 @@ fn dec(s: &str) -> Decimal {
     Decimal::from_str(s).unwrap()
 }
 
-/// Account state returned by the test engine.
-#[derive(Debug)]
+/// Account state as written by the engine.
+#[derive(Debug, serde::Deserialize)]
 struct AccountState {
+    client: u16,
     available: Decimal,
     held: Decimal,
     total: Decimal,
     locked: bool,
 }
 
-/// Run the full engine pipeline on raw CSV input and return parsed output rows.
+/// Run raw CSV input through `PaymentsEngine` with the CLI's reader settings, and parse
+/// the rows `write_output` produces back into account states keyed by client.
 fn run_engine(csv_input: &str) -> HashMap<u16, AccountState> {
+    use accounts_manager::{PaymentsEngine, TransactionRecord};
     use csv::ReaderBuilder;
-    use serde::Deserialize;
-
-    #[derive(Debug, Clone, Deserialize, PartialEq)]
-    #[serde(rename_all = "lowercase")]
-    enum TransactionType {
-        Deposit,
-        Withdrawal,
-        Dispute,
-        Resolve,
-        Chargeback,
-    }
-
-    #[derive(Debug, Clone, Deserialize)]
-    struct TransactionRecord {
-        r#type: TransactionType,
-        client: u16,
-        tx: u32,
-        amount: Option<Decimal>,
-    }
-
-    #[derive(Debug, Clone)]
-    struct StoredTransaction {
-        client: u16,
-        amount: Decimal,
-        under_dispute: bool,
-    }
-
-    #[derive(Debug, Clone)]
-    struct ClientAccount {
-        available: Decimal,
-        held: Decimal,
-        total: Decimal,
-        locked: bool,
-    }
 
-    impl ClientAccount {
-        fn new() -> Self {
-            Self {
-                available: Decimal::ZERO,
-                held: Decimal::ZERO,
-                total: Decimal::ZERO,
-                locked: false,
-            }
-        }
-        fn deposit(&mut self, amount: Decimal) {
-            self.available += amount;
-            self.total += amount;
-        }
-        fn withdraw(&mut self, amount: Decimal) -> bool {
-            if self.available >= amount {
-                self.available -= amount;
-                self.total -= amount;
-                true
-            } else {
-                false
-            }
-        }
-        fn hold(&mut self, amount: Decimal) {
-            self.available -= amount;
-            self.held += amount;
-        }
-        fn release(&mut self, amount: Decimal) {
-            self.held -= amount;
-            self.available += amount;
-        }
-        fn chargeback(&mut self, amount: Decimal) {
-            self.held -= amount;
-            self.total -= amount;
-            self.locked = true;
-        }
-    }
-
-    let mut clients: HashMap<u16, ClientAccount> = HashMap::new();
-    let mut transactions: HashMap<u32, StoredTransaction> = HashMap::new();
-
-    let cursor = Cursor::new(csv_input);
+    let mut engine = PaymentsEngine::new();
     let mut reader = ReaderBuilder::new()
         .trim(csv::Trim::All)
-        .flexible(true)
-        .from_reader(cursor);
-
-    for result in reader.deserialize::<TransactionRecord>() {
-        let record = match result {
-            Ok(r) => r,
-            Err(_) => continue,
-        };
-
-        match record.r#type {
-            TransactionType::Deposit => {
-                if let Some(amount) = record.amount {
-                    let acct = clients
-                        .entry(record.client)
-                        .or_insert_with(ClientAccount::new);
-                    if !acct.locked {
-                        acct.deposit(amount);
-                        transactions.insert(
-                            record.tx,
-                            StoredTransaction {
-                                client: record.client,
-                                amount,
-                                under_dispute: false,
-                            },
-                        );
-                    }
-                }
-            }
-            TransactionType::Withdrawal => {
-                if let Some(amount) = record.amount {
-                    let acct = clients
-                        .entry(record.client)
-                        .or_insert_with(ClientAccount::new);
-                    if !acct.locked {
-                        acct.withdraw(amount);
-                    }
-                }
-            }
-            TransactionType::Dispute => {
-                if let Some(stored) = transactions.get_mut(&record.tx) {
-                    if stored.client != record.client {
-                        continue;
-                    }
-                    if stored.under_dispute {
-                        continue;
-                    }
-                    if let Some(acct) = clients.get_mut(&record.client) {
-                        if acct.locked {
-                            continue;
-                        }
-                        stored.under_dispute = true;
-                        acct.hold(stored.amount);
-                    }
-                }
-            }
-            TransactionType::Resolve => {
-                if let Some(stored) = transactions.get_mut(&record.tx) {
-                    if stored.client != record.client {
-                        continue;
-                    }
-                    if !stored.under_dispute {
-                        continue;
-                    }
-                    if let Some(acct) = clients.get_mut(&record.client) {
-                        if acct.locked {
-                            continue;
-                        }
-                        stored.under_dispute = false;
-                        acct.release(stored.amount);
-                    }
-                }
-            }
-            TransactionType::Chargeback => {
-                if let Some(stored) = transactions.get_mut(&record.tx) {
-                    if stored.client != record.client {
-                        continue;
-                    }
-                    if !stored.under_dispute {
-                        continue;
-                    }
-                    if let Some(acct) = clients.get_mut(&record.client) {
-                        if acct.locked {
-                            continue;
-                        }
-                        stored.under_dispute = false;
-                        acct.chargeback(stored.amount);
-                    }
-                }
-            }
-        }
+        .flexible(false)
+        .from_reader(Cursor::new(csv_input));
+    for record in reader.deserialize::<TransactionRecord>().flatten() {
+        // Rejections leave balances untouched, as the CLI does
+        let _ = engine.process(record);
     }
 
-    let mut result_map = HashMap::new();
-    for (&cid, acct) in &clients {
-        result_map.insert(
-            cid,
-            AccountState {
-                available: acct.available,
-                held: acct.held,
-                total: acct.total,
-                locked: acct.locked,
-            },
-        );
-    }
-    result_map
+    let mut output = Vec::new();
+    engine.write_output(&mut output).unwrap();
+    ReaderBuilder::new()
+        .from_reader(output.as_slice())
+        .deserialize::<AccountState>()
+        .map(|row| {
+            let state = row.unwrap();
+            (state.client, state)
+        })
+        .collect()
 }
 
 // ─── Test Cases ──────────────────────────────────────────────────────────────
@@ -402,3 +243,2172 @@ dispute, 2, 1,
     assert_eq!(c1.available, dec("10.0"));
     assert_eq!(c1.held, dec("0"));
 }
+
+// ─── Library Tests ───────────────────────────────────────────────────────────
+
+#[test]
+fn test_library_engine_dispute_and_chargeback() {
+    use accounts_manager::{PaymentsEngine, TransactionRecord, TransactionType};
+
+    let mut engine = PaymentsEngine::new();
+    for (r#type, tx, amount) in [
+        (TransactionType::Deposit, 1, Some(dec("10.0"))),
+        (TransactionType::Deposit, 2, Some(dec("4.5"))),
+        (TransactionType::Dispute, 1, None),
+        (TransactionType::Chargeback, 1, None),
+    ] {
+        engine
+            .process(TransactionRecord {
+                r#type,
+                client: 1,
+                tx,
+                amount,
+            })
+            .unwrap();
+    }
+
+    let records = engine.output_records();
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].available, dec("4.5"));
+    assert_eq!(records[0].held, dec("0"));
+    assert_eq!(records[0].total, dec("4.5"));
+    assert!(records[0].locked);
+}
+
+#[test]
+fn test_library_account_queries_mid_stream() {
+    use accounts_manager::{PaymentsEngine, TransactionRecord, TransactionType};
+
+    let mut engine = PaymentsEngine::new();
+    assert!(engine.account(1).is_none());
+
+    let record = |r#type, client, tx, amount: Option<&str>| TransactionRecord {
+        r#type,
+        client,
+        tx,
+        amount: amount.map(dec),
+    };
+    engine
+        .process(record(TransactionType::Deposit, 1, 1, Some("10.0")))
+        .unwrap();
+    engine
+        .process(record(TransactionType::Deposit, 2, 2, Some("3.0")))
+        .unwrap();
+
+    let before = engine.account(1).unwrap();
+    assert_eq!(before.available, dec("10.0"));
+    assert_eq!(before.held, dec("0"));
+
+    engine
+        .process(record(TransactionType::Dispute, 1, 1, None))
+        .unwrap();
+
+    // The earlier snapshot is a copy, unaffected by later processing.
+    assert_eq!(before.available, dec("10.0"));
+    let after = engine.account(1).unwrap();
+    assert_eq!(after.available, dec("0"));
+    assert_eq!(after.held, dec("10.0"));
+    assert_eq!(after.total, dec("10.0"));
+    assert!(!after.locked);
+
+    let mut ids: Vec<u16> = engine.client_ids().collect();
+    ids.sort_unstable();
+    assert_eq!(ids, [1, 2]);
+}
+
+#[test]
+fn test_library_engine_with_custom_store() {
+    use std::collections::BTreeMap;
+
+    use accounts_manager::types::StoredTransaction;
+    use accounts_manager::{PaymentsEngine, TransactionRecord, TransactionType, TxStore};
+
+    /// A store that counts the writes reaching it, standing in for an external one.
+    #[derive(Default)]
+    struct CountingStore {
+        entries: BTreeMap<u32, StoredTransaction>,
+        writes: usize,
+    }
+
+    impl TxStore for CountingStore {
+        fn get(&self, tx: u32) -> Option<StoredTransaction> {
+            self.entries.get(&tx).cloned()
+        }
+
+        fn insert(&mut self, tx: u32, stored: StoredTransaction) {
+            self.writes += 1;
+            self.entries.insert(tx, stored);
+        }
+
+        fn set_disputed(&mut self, tx: u32, under_dispute: bool) {
+            self.writes += 1;
+            if let Some(stored) = self.entries.get_mut(&tx) {
+                stored.under_dispute = under_dispute;
+            }
+        }
+
+        fn remove(&mut self, tx: u32) {
+            self.writes += 1;
+            self.entries.remove(&tx);
+        }
+    }
+
+    let record = |r#type, client, tx, amount: Option<&str>| TransactionRecord {
+        r#type,
+        client,
+        tx,
+        amount: amount.map(dec),
+    };
+    let mut engine = PaymentsEngine::with_store(CountingStore::default());
+    for record in [
+        record(TransactionType::Deposit, 1, 1, Some("10.0")),
+        record(TransactionType::Deposit, 1, 2, Some("4.0")),
+        record(TransactionType::Withdrawal, 1, 3, Some("3.0")),
+        record(TransactionType::Dispute, 1, 1, None),
+        record(TransactionType::Dispute, 1, 2, None),
+        record(TransactionType::Resolve, 1, 2, None),
+    ] {
+        engine.process(record).unwrap();
+    }
+    // A reused tx ID is still caught through the store
+    assert!(engine
+        .process(record(TransactionType::Deposit, 1, 2, Some("1.0")))
+        .is_err());
+
+    let account = engine.account(1).unwrap();
+    assert_eq!(account.available, dec("1.0"));
+    assert_eq!(account.held, dec("10.0"));
+    assert_eq!(account.total, dec("11.0"));
+
+    // Three inserts, then two disputes and a resolve flagging entries
+    let store = engine.store();
+    assert_eq!(store.writes, 6);
+    assert!(store.entries[&1].under_dispute);
+    assert!(!store.entries[&2].under_dispute);
+}
+
+#[test]
+fn test_transaction_type_ignores_case_and_whitespace() {
+    use accounts_manager::{TransactionRecord, TransactionType};
+
+    // No trimming on the reader, so the padding reaches the deserializer.
+    let input = "type,client,tx,amount\nDeposit,1,1,10.0\nWITHDRAWAL,1,2,1.0\n dispute ,1,1,\n";
+    let mut reader = csv::ReaderBuilder::new().from_reader(input.as_bytes());
+    let types: Vec<TransactionType> = reader
+        .deserialize::<TransactionRecord>()
+        .map(|record| record.unwrap().r#type)
+        .collect();
+    assert_eq!(
+        types,
+        [
+            TransactionType::Deposit,
+            TransactionType::Withdrawal,
+            TransactionType::Dispute
+        ]
+    );
+
+    let mut reader =
+        csv::ReaderBuilder::new().from_reader("type,client,tx,amount\nrefund,1,3,1.0\n".as_bytes());
+    let err = reader
+        .deserialize::<TransactionRecord>()
+        .next()
+        .unwrap()
+        .unwrap_err();
+    assert!(err.to_string().contains("unknown variant `refund`"));
+}
+
+// ─── CLI Tests ───────────────────────────────────────────────────────────────
+
+/// Write `contents` to a fresh file under the test scratch directory and return its path.
+fn write_input(name: &str, contents: &str) -> std::path::PathBuf {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(name);
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+/// Run the compiled binary with the given arguments and return its captured output.
+fn run_cli(args: &[&std::ffi::OsStr]) -> std::process::Output {
+    std::process::Command::new(env!("CARGO_BIN_EXE_accounts_manager"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn test_allow_fractions_parses_ratio_amounts() {
+    let path = write_input(
+        "allow_fractions.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 1/4
+deposit, 2, 2, 1/0
+",
+    );
+    let out = run_cli(&[path.as_os_str(), "--allow-fractions".as_ref()]);
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n1,0.25,0,0.25,false\n"
+    );
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("invalid fraction `1/0`"));
+}
+
+#[test]
+fn test_require_fractional_rejects_whole_amounts() {
+    let path = write_input(
+        "require_fractional.csv",
+        "\
+type, client, tx, amount
+deposit,     1,  1,   5
+deposit,     1,  2,   5.00
+withdrawal,  1,  3,   1
+dispute,     1,  2,
+",
+    );
+    let out = run_cli(&[path.as_os_str(), "--require-fractional".as_ref()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0,5,5,false\n"
+    );
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("skipping tx 1: amount 5 has no fractional part"));
+    assert!(stderr.contains("skipping tx 3: amount 1 has no fractional part"));
+    assert!(stderr.contains("skipped 2 of 4 rows"));
+}
+
+#[test]
+fn test_integer_amounts_rejects_fractional_amounts() {
+    let path = write_input(
+        "integer_amounts.csv",
+        "\
+type, client, tx, amount
+deposit,     1,  1,   5
+deposit,     1,  2,   5.0
+deposit,     1,  3,   5.50
+withdrawal,  1,  4,   0.25
+withdrawal,  1,  5,   2
+",
+    );
+    let out = run_cli(&[path.as_os_str(), "--integer-amounts".as_ref()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,8,0,8,false\n"
+    );
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("skipping tx 3: amount has a fractional part"));
+    assert!(stderr.contains("skipping tx 4: amount has a fractional part"));
+    assert!(stderr.contains("skipped 2 of 5 rows"), "{stderr}");
+}
+
+#[test]
+fn test_activity_bounds_flag_merged_datasets() {
+    let path = write_input(
+        "merged_datasets.csv",
+        "\
+type, client, tx, amount
+deposit,    1, 1, 20.0
+withdrawal, 1, 2,  5.0
+deposit,    2, 3, 10.0
+deposit,    2, 4, 90000.0
+withdrawal, 2, 5, 90000.0
+",
+    );
+    let out = run_cli(&[
+        path.as_os_str(),
+        "--max-balance-swing".as_ref(),
+        "1000".as_ref(),
+    ]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,15,0,15,false\n2,10,0,10,false\n"
+    );
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("anomaly: client 2 has 3 rows and a balance swing of 90010"));
+    assert!(!stderr.contains("client 1 has"));
+
+    let out = run_cli(&[
+        path.as_os_str(),
+        "--max-client-transactions".as_ref(),
+        "2".as_ref(),
+    ]);
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("anomaly: client 2 has 3 rows"));
+    assert!(!stderr.contains("client 1 has"));
+
+    // An opening balance is where the swing starts, not part of it
+    let opening = write_input(
+        "merged_datasets_opening.csv",
+        "client, available, held, locked\n1, 5000.0, 0, false\n",
+    );
+    let out = run_cli(&[
+        path.as_os_str(),
+        "--opening".as_ref(),
+        opening.as_os_str(),
+        "--max-balance-swing".as_ref(),
+        "1000".as_ref(),
+    ]);
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(!stderr.contains("client 1 has"), "{stderr}");
+}
+
+#[test]
+fn test_count_only_reports_per_type_counts() {
+    let path = write_input(
+        "count_only.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+withdrawal, 1, 3, 1.0
+dispute, 1, 1,
+resolve, 1, 1,
+dispute, 2, 2,
+chargeback, 2, 2,
+deposit, 1, 4, 1.0
+",
+    );
+    let out = run_cli(&[path.as_os_str(), "--count-only".as_ref()]);
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "\
+type,count
+deposit,3
+withdrawal,1
+dispute,2
+resolve,1
+chargeback,1
+"
+    );
+}
+
+#[test]
+fn test_resolutions_file_charges_back_disputed_tx() {
+    let input = write_input(
+        "resolutions_input.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 1, 2, 5.0
+dispute, 1, 1,
+",
+    );
+    let resolutions = write_input(
+        "resolutions.csv",
+        "\
+tx, outcome
+1, chargeback
+2, chargeback
+",
+    );
+    let out = run_cli(&[
+        input.as_os_str(),
+        "--resolutions".as_ref(),
+        resolutions.as_os_str(),
+    ]);
+    assert!(out.status.success());
+
+    // tx 2 was never disputed, so only tx 1 is charged back.
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(stdout, "client,available,held,total,locked\n1,5,0,5,true\n");
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("skipping resolution of tx 2:"), "{stderr}");
+    assert!(stderr.contains("skipped 1 of 5 rows"), "{stderr}");
+
+    let out = run_cli(&[
+        input.as_os_str(),
+        "--resolutions".as_ref(),
+        resolutions.as_os_str(),
+        "--strict".as_ref(),
+    ]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+}
+
+#[test]
+fn test_quiet_suppresses_malformed_row_warnings() {
+    let path = write_input(
+        "quiet.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 1, 2, not-a-number
+",
+    );
+
+    let loud = run_cli(&[path.as_os_str()]);
+    assert!(!loud.stderr.is_empty());
+
+    // The per-row warning is suppressed, but not the skip summary
+    let quiet = run_cli(&[path.as_os_str(), "--quiet".as_ref()]);
+    assert!(quiet.status.success());
+    assert_eq!(
+        String::from_utf8(quiet.stderr).unwrap(),
+        "warning: skipped 1 of 2 rows\n"
+    );
+    assert_eq!(quiet.stdout, loud.stdout);
+}
+
+#[test]
+fn test_quiet_and_strict_are_rejected_together() {
+    let path = write_input("quiet_strict.csv", "type, client, tx, amount\n");
+    let out = run_cli(&["--quiet".as_ref(), "--strict".as_ref(), path.as_os_str()]);
+    assert_eq!(out.status.code(), Some(2));
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("the argument '--quiet' cannot be used with '--strict'"),
+        "{stderr}"
+    );
+
+    let out = run_cli(&["--strict".as_ref(), "--quiet".as_ref(), path.as_os_str()]);
+    assert_eq!(out.status.code(), Some(2));
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("the argument '--strict' cannot be used with '--quiet'"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_net_reports_gross_flows() {
+    let path = write_input(
+        "net.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 1, 2, 5.5
+withdrawal, 1, 3, 3.0
+withdrawal, 1, 4, 100.0
+withdrawal, 1, 5, 2.5
+",
+    );
+    let out = run_cli(&[path.as_os_str(), "--net".as_ref()]);
+    assert!(out.status.success());
+
+    // The rejected 100.0 withdrawal is not counted as an outflow.
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(stdout, "client,gross_in,gross_out,net\n1,15.5,5.5,10.0\n");
+}
+
+#[cfg(feature = "tar")]
+#[test]
+fn test_tar_archive_members_are_combined() {
+    let path = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("daily.tar.gz");
+    let gz = flate2::write::GzEncoder::new(
+        std::fs::File::create(&path).unwrap(),
+        flate2::Compression::default(),
+    );
+    let mut archive = tar::Builder::new(gz);
+
+    // Appended out of name order to confirm members are sorted before processing.
+    let members: [(&str, &str); 3] = [
+        (
+            "b.csv",
+            "type,client,tx,amount\ndispute,1,1,\nwithdrawal,2,4,1.0\n",
+        ),
+        (
+            "a.csv",
+            "type,client,tx,amount\ndeposit,1,1,10.0\ndeposit,2,2,3.0\n",
+        ),
+        ("notes.txt", "not a csv"),
+    ];
+    for (name, contents) in members {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        archive
+            .append_data(&mut header, name, contents.as_bytes())
+            .unwrap();
+    }
+    archive.into_inner().unwrap().finish().unwrap();
+
+    let out = run_cli(&["--tar".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let mut rows: Vec<&str> = stdout.lines().skip(1).collect();
+    rows.sort();
+    assert_eq!(rows, ["1,0,10,10,false", "2,2,0,2,false"]);
+}
+
+#[test]
+fn test_output_file_and_stdout_receive_identical_rows() {
+    let input = write_input(
+        "tee_input.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 3.5
+withdrawal, 1, 3, 4.0
+",
+    );
+    let output = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("tee_output.csv");
+    let out = run_cli(&[
+        input.as_os_str(),
+        "--output".as_ref(),
+        output.as_os_str(),
+        "--also-stdout".as_ref(),
+    ]);
+    assert!(out.status.success());
+
+    let written = std::fs::read(&output).unwrap();
+    assert!(!written.is_empty());
+    assert_eq!(out.stdout, written);
+}
+
+#[test]
+fn test_output_buffer_size_does_not_change_output() {
+    let mut input = String::from("type, client, tx, amount\n");
+    for client in 1..=2000u32 {
+        input.push_str(&format!("deposit, {client}, {client}, {client}.25\n"));
+    }
+    let path = write_input("output_buffer.csv", &input);
+    let unbuffered = run_cli(&[path.as_os_str()]);
+    assert!(unbuffered.status.success());
+
+    for bytes in ["1", "4096", "1048576"] {
+        let output = std::path::Path::new(env!("CARGO_TARGET_TMPDIR"))
+            .join(format!("output_buffer_{bytes}.csv"));
+        let out = run_cli(&[
+            path.as_os_str(),
+            "--output-buffer".as_ref(),
+            bytes.as_ref(),
+            "--output".as_ref(),
+            output.as_os_str(),
+            "--also-stdout".as_ref(),
+        ]);
+        assert!(out.status.success());
+        assert_eq!(out.stdout, unbuffered.stdout, "--output-buffer {bytes}");
+        assert_eq!(std::fs::read(&output).unwrap(), unbuffered.stdout);
+    }
+}
+
+#[test]
+fn test_timeout_flushes_partial_output_and_fails() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    use std::time::Duration;
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_accounts_manager"))
+        .args(["--timeout", "0.5"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin
+        .write_all(b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 2.0\n")
+        .unwrap();
+    stdin.flush().unwrap();
+
+    // The input stalls past the deadline; the next row to arrive ends the run.
+    std::thread::sleep(Duration::from_millis(800));
+    let _ = stdin.write_all(b"deposit, 1, 3, 4.0\ndeposit, 1, 4, 8.0\n");
+    drop(stdin);
+
+    let out = child.wait_with_output().unwrap();
+    assert_eq!(out.status.code(), Some(1));
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,3,0,3,false\n"
+    );
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("timed out after 500ms"), "{stderr}");
+}
+
+#[test]
+fn test_timeout_applies_across_input_files() {
+    let first = write_input(
+        "timeout_first.csv",
+        "type, client, tx, amount\ndeposit, 1, 1, 1.0\n",
+    );
+    let second = write_input(
+        "timeout_second.csv",
+        "type, client, tx, amount\ndeposit, 1, 2, 1.0\n",
+    );
+
+    let out = run_cli(&[
+        first.as_os_str(),
+        second.as_os_str(),
+        "--timeout".as_ref(),
+        "0".as_ref(),
+    ]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+
+    let untimed = run_cli(&[
+        first.as_os_str(),
+        second.as_os_str(),
+        "--timeout".as_ref(),
+        "60".as_ref(),
+    ]);
+    assert!(untimed.status.success());
+    assert_eq!(
+        String::from_utf8(untimed.stdout).unwrap(),
+        "client,available,held,total,locked\n1,2,0,2,false\n"
+    );
+}
+
+#[test]
+fn test_output_is_sorted_and_stable_across_runs() {
+    let mut input = String::from("type, client, tx, amount\n");
+    for client in (1..=50u32).rev() {
+        input.push_str(&format!("deposit, {client}, {client}, {client}.5\n"));
+    }
+    let path = write_input("stable_order.csv", &input);
+
+    let first = run_cli(&[path.as_os_str()]);
+    let second = run_cli(&[path.as_os_str()]);
+    assert!(first.status.success());
+    assert_eq!(first.stdout, second.stdout);
+
+    let stdout = String::from_utf8(first.stdout).unwrap();
+    let clients: Vec<u16> = stdout
+        .lines()
+        .skip(1)
+        .map(|line| line.split(',').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(clients, (1..=50).collect::<Vec<u16>>());
+}
+
+#[cfg(feature = "mmap")]
+#[test]
+fn test_mmap_output_matches_buffered_read() {
+    let mut input = String::from("type, client, tx, amount\n");
+    for tx in 1..=500u32 {
+        let client = tx % 7;
+        input.push_str(&format!("deposit, {client}, {tx}, {tx}.25\n"));
+        if tx % 5 == 0 {
+            input.push_str(&format!("dispute, {client}, {tx},\n"));
+        }
+    }
+    let path = write_input("mmap.csv", &input);
+
+    let buffered = run_cli(&[path.as_os_str()]);
+    let mapped = run_cli(&[path.as_os_str(), "--mmap".as_ref()]);
+    assert!(mapped.status.success());
+    assert_eq!(mapped.stdout, buffered.stdout);
+}
+
+#[test]
+fn test_output_amounts_round_to_four_places() {
+    let path = write_input(
+        "round_output.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 1.11115
+deposit, 2, 2, 2.123449
+deposit, 3, 3, 3.5
+",
+    );
+    let out = run_cli(&[path.as_os_str()]);
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "\
+client,available,held,total,locked
+1,1.1112,0,1.1112,false
+2,2.1234,0,2.1234,false
+3,3.5,0,3.5,false
+"
+    );
+}
+
+#[test]
+fn test_non_positive_amounts_are_rejected_with_warning() {
+    let path = write_input(
+        "non_positive.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, -50.0
+deposit, 1, 2, 0.0
+deposit, 1, 3, 20.0
+withdrawal, 1, 4, -5.0
+",
+    );
+    let out = run_cli(&[path.as_os_str()]);
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "client,available,held,total,locked\n1,20,0,20,false\n"
+    );
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    for tx in [1, 2, 4] {
+        assert!(stderr.contains(&format!("skipping tx {tx}:")), "{stderr}");
+    }
+    assert!(!stderr.contains("skipping tx 3:"));
+}
+
+#[test]
+fn test_bless_then_diff_reports_no_differences() {
+    let input = write_input(
+        "bless_input.csv",
+        "\
+type, client, tx, amount
+deposit, 2, 1, 5.0
+deposit, 1, 2, 3.5
+withdrawal, 2, 3, 1.25
+",
+    );
+    let golden = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("bless_golden.csv");
+
+    let out = run_cli(&["bless".as_ref(), input.as_os_str(), golden.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&golden).unwrap(),
+        "client,available,held,total,locked\n1,3.5,0,3.5,false\n2,3.75,0,3.75,false\n"
+    );
+
+    let out = run_cli(&["diff".as_ref(), input.as_os_str(), golden.as_os_str()]);
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+
+    let changed = write_input(
+        "bless_changed.csv",
+        "\
+type, client, tx, amount
+deposit, 2, 1, 5.0
+deposit, 1, 2, 4.0
+",
+    );
+    let out = run_cli(&["diff".as_ref(), changed.as_os_str(), golden.as_os_str()]);
+    assert!(!out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "-1,3.5,0,3.5,false\n+1,4,0,4,false\n-2,3.75,0,3.75,false\n+2,5,0,5,false\n"
+    );
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("2 row(s) differ"));
+}
+
+#[test]
+fn test_diff_exits_quietly_on_closed_stdout() {
+    use std::process::{Command, Stdio};
+
+    // Enough differing rows to fill the pipe after the reader has gone
+    let mut contents = String::from("type, client, tx, amount\n");
+    for client in 1..=20_000u32 {
+        contents.push_str(&format!("deposit, {client}, {client}, 1.0\n"));
+    }
+    let input = write_input("diff_closed_stdout.csv", &contents);
+    let golden = write_input("diff_closed_stdout_golden.csv", "");
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_accounts_manager"))
+        .args(["diff".as_ref(), input.as_os_str(), golden.as_os_str()])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    drop(child.stdout.take());
+
+    let out = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(out.status.success(), "{stderr}");
+    assert!(!stderr.contains("panicked"), "{stderr}");
+}
+
+#[test]
+fn test_opening_balances_accept_yes_no_locked() {
+    let opening = write_input(
+        "opening_yes_no.csv",
+        "\
+client, available, held, total, locked
+1, 10.0, 2.5, 12.5, no
+2, 7.0, 0, 7.0, YES
+3, 1.0, 0, 1.0, maybe
+",
+    );
+    let input = write_input(
+        "opening_input.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 5.0
+deposit, 2, 2, 5.0
+",
+    );
+    let out = run_cli(&["--opening".as_ref(), opening.as_os_str(), input.as_os_str()]);
+    assert!(out.status.success());
+
+    // The locked account ignores its deposit; the malformed row is skipped.
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,15,2.5,17.5,false\n2,7,0,7,true\n"
+    );
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("skipping malformed opening balance"));
+}
+
+#[test]
+fn test_opening_balance_with_overflowing_total_is_skipped() {
+    let opening = write_input(
+        "opening_overflow.csv",
+        "\
+client, available, held, locked
+1, 60000000000000000000000000000.0, 60000000000000000000000000000.0, false
+2, 3.0, 1.0, false
+",
+    );
+    let input = write_input("opening_overflow_input.csv", "type, client, tx, amount\n");
+    let out = run_cli(&["--opening".as_ref(), opening.as_os_str(), input.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n2,3,1,4,false\n"
+    );
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains(
+            "skipping opening balance for client 1: amount would overflow the account balance"
+        ),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_missing_opening_file_names_its_path() {
+    let input = write_input("opening_missing_input.csv", "type, client, tx, amount\n");
+    let out = run_cli(&[
+        "--opening".as_ref(),
+        "no_such_opening.csv".as_ref(),
+        input.as_os_str(),
+    ]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("cannot open no_such_opening.csv:"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_max_balance_caps_deposits() {
+    let path = write_input(
+        "max_balance.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 80.0
+deposit, 1, 2, 30.0
+deposit, 2, 3, 50.0
+",
+    );
+    let out = run_cli(&["--max-balance".as_ref(), "100".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,80,0,80,false\n2,50,0,50,false\n"
+    );
+}
+
+#[test]
+fn test_reads_stdin_without_input_file() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_accounts_manager"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(b"type, client, tx, amount\ndeposit, 1, 1, 2.5\nwithdrawal, 1, 2, 1.0\n")
+        .unwrap();
+
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1.5,0,1.5,false\n"
+    );
+}
+
+#[test]
+fn test_holds_over_lists_accounts_above_threshold() {
+    let path = write_input(
+        "holds_over.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 30.0
+deposit, 2, 2, 5.0
+deposit, 3, 3, 80.0
+dispute, 1, 1,
+dispute, 2, 2,
+dispute, 3, 3,
+",
+    );
+    let out = run_cli(&["--holds-over".as_ref(), "10".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n3,0,80,80,false\n1,0,30,30,false\n"
+    );
+}
+
+#[test]
+fn test_with_metadata_appends_comment_line() {
+    let path = write_input(
+        "with_metadata.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 2.0
+deposit, 1, 2, 3.0
+dispute, 1, 9,
+",
+    );
+    let out = run_cli(&["--with-metadata".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let (rows, metadata) = stdout.split_once("# ").unwrap();
+    assert_eq!(rows, "client,available,held,total,locked\n1,5,0,5,false\n");
+
+    let fields: HashMap<&str, &str> = metadata
+        .trim_end()
+        .split(',')
+        .map(|field| field.split_once('=').unwrap())
+        .collect();
+    assert!(fields["run_at"].parse::<u64>().unwrap() > 0);
+    assert_eq!(fields["input"], path.to_str().unwrap());
+    assert_eq!(fields["records"], "3");
+    assert_eq!(fields["version"], env!("CARGO_PKG_VERSION"));
+}
+
+#[test]
+fn test_skipped_rows_summary_and_strict() {
+    let path = write_input(
+        "mixed_quality.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 1, 2, abc
+refund, 1, 3, 1.0
+deposit, 1, 4, -2.0
+withdrawal, 1, 5, 99.0
+deposit, 2, 6, 1.0
+",
+    );
+    let out = run_cli(&[path.as_os_str()]);
+    assert!(out.status.success());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    // Insufficient funds is an ordinary rejection, not a skipped row.
+    assert!(
+        stderr.ends_with("warning: skipped 3 of 6 rows\n"),
+        "{stderr}"
+    );
+
+    let out = run_cli(&["--strict".as_ref(), path.as_os_str()]);
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("error: skipped 3 of 6 rows in strict mode"));
+}
+
+#[test]
+fn test_input_without_a_parseable_row_fails() {
+    let path = write_input(
+        "semicolons.csv",
+        "\
+type;client;tx;amount
+deposit;1;1;10.0
+deposit;2;2;5.0
+",
+    );
+    let out = run_cli(&[path.as_os_str()]);
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("error: none of the 2 input rows could be parsed"));
+}
+
+#[test]
+fn test_require_amount_column_rejects_amountless_header() {
+    let path = write_input(
+        "no_amount_column.csv",
+        "\
+type, client, tx
+deposit, 1, 1
+",
+    );
+    let out = run_cli(&["--require-amount-column".as_ref(), path.as_os_str()]);
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("error: input has no `amount` column"));
+
+    // Without the flag, the run is warned about the header, and the fourth column is
+    // not rewritten as though it held the amount
+    let path = write_input(
+        "no_amount_column_memo.csv",
+        "\
+type; client; tx; memo
+deposit; 1; 1; 5.00
+",
+    );
+    let out = run_cli(&[
+        "--delimiter".as_ref(),
+        ";".as_ref(),
+        "--decimal-comma".as_ref(),
+        path.as_os_str(),
+    ]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("warning: input has no `amount` column, so its rows cannot be parsed"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("missing field `amount`"), "{stderr}");
+    assert!(!stderr.contains("thousands separator"), "{stderr}");
+}
+
+#[test]
+fn test_json_format_round_trips_amounts() {
+    let path = write_input(
+        "json_format.csv",
+        "\
+type, client, tx, amount
+deposit, 2, 1, 1.5
+deposit, 1, 2, 10.1234
+dispute, 1, 2,
+",
+    );
+    let out = run_cli(&["--format".as_ref(), "json".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+
+    let rows: Vec<serde_json::Value> = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0]["client"], 1);
+    assert_eq!(dec(rows[0]["available"].as_str().unwrap()), dec("0"));
+    assert_eq!(dec(rows[0]["held"].as_str().unwrap()), dec("10.1234"));
+    assert_eq!(dec(rows[0]["total"].as_str().unwrap()), dec("10.1234"));
+    assert_eq!(rows[0]["locked"], false);
+    assert_eq!(rows[1]["client"], 2);
+    assert_eq!(dec(rows[1]["available"].as_str().unwrap()), dec("1.5"));
+}
+
+#[test]
+fn test_json_metadata_wraps_accounts() {
+    let path = write_input(
+        "json_metadata.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 2.0
+",
+    );
+    let out = run_cli(&[
+        "--format".as_ref(),
+        "json".as_ref(),
+        "--with-metadata".as_ref(),
+        path.as_os_str(),
+    ]);
+    assert!(out.status.success());
+
+    let output: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(output["accounts"][0]["client"], 1);
+    assert_eq!(output["metadata"]["input"], path.to_str().unwrap());
+    assert_eq!(output["metadata"]["records"], 1);
+    assert_eq!(output["metadata"]["version"], env!("CARGO_PKG_VERSION"));
+    assert!(output["metadata"]["run_at"].as_u64().unwrap() > 0);
+}
+
+#[test]
+fn test_with_schema_version_declares_output_layout() {
+    use accounts_manager::types::OUTPUT_SCHEMA_VERSION;
+
+    let path = write_input(
+        "schema_version.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 2.0
+deposit, 2, 2, 1.5
+",
+    );
+    let out = run_cli(&[path.as_os_str(), "--with-schema-version".as_ref()]);
+    assert!(out.status.success());
+
+    let stdout = String::from_utf8(out.stdout).unwrap();
+    let (marker, rows) = stdout.split_once('\n').unwrap();
+    assert_eq!(marker, format!("# schema_version={OUTPUT_SCHEMA_VERSION}"));
+    let clients: Vec<u16> = csv::Reader::from_reader(rows.as_bytes())
+        .records()
+        .map(|row| row.unwrap()[0].parse().unwrap())
+        .collect();
+    assert_eq!(clients, [1, 2]);
+
+    let out = run_cli(&[
+        path.as_os_str(),
+        "--with-schema-version".as_ref(),
+        "--format".as_ref(),
+        "json".as_ref(),
+    ]);
+    let output: serde_json::Value = serde_json::from_slice(&out.stdout).unwrap();
+    assert_eq!(output["schema_version"], OUTPUT_SCHEMA_VERSION);
+    assert_eq!(output["accounts"][1]["available"], "1.5");
+}
+
+#[test]
+fn test_only_type_applies_matching_records_on_opening_balances() {
+    let opening = write_input(
+        "only_type_opening.csv",
+        "\
+client, available, held, total, locked
+1, 100.0, 0, 100.0, false
+",
+    );
+    let input = write_input(
+        "only_type_input.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 50.0
+withdrawal, 1, 2, 30.0
+dispute, 1, 1,
+chargeback, 1, 1,
+withdrawal, 1, 3, 20.0
+",
+    );
+
+    let out = run_cli(&[
+        "--opening".as_ref(),
+        opening.as_os_str(),
+        "--only-type".as_ref(),
+        "withdrawal".as_ref(),
+        input.as_os_str(),
+    ]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,50,0,50,false\n"
+    );
+
+    // Without the deposit and dispute, the chargeback has nothing to reverse.
+    let out = run_cli(&[
+        "--opening".as_ref(),
+        opening.as_os_str(),
+        "--only-type".as_ref(),
+        "chargeback".as_ref(),
+        input.as_os_str(),
+    ]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,100,0,100,false\n"
+    );
+}
+
+#[test]
+fn test_verbose_adds_tx_count_column() {
+    let path = write_input(
+        "verbose_tx_count.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 3.0
+withdrawal, 1, 3, 30.0
+dispute, 1, 9,
+deposit, 2, 4, 1.0
+",
+    );
+    let out = run_cli(&["--verbose".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked,tx_count\n1,7,0,7,false,2\n2,1,0,1,false,1\n"
+    );
+}
+
+#[test]
+fn test_missing_and_unexpected_amounts_are_warned_about() {
+    let path = write_input(
+        "amount_presence.csv",
+        "\
+type, client, tx, amount
+deposit,    1, 1, 10.0
+deposit,    1, 2,
+withdrawal, 1, 3,
+dispute,    1, 1, 10.0
+",
+    );
+    let out = run_cli(&[path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0,10,10,false\n"
+    );
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(stderr.contains("skipping tx 2: deposit or withdrawal has no amount"));
+    assert!(stderr.contains("skipping tx 3: deposit or withdrawal has no amount"));
+    assert!(stderr.contains("dispute tx 1 has amount 10, which is ignored"));
+    assert!(stderr.contains("skipped 2 of 4 rows"));
+}
+
+#[test]
+fn test_insufficient_funds_withdrawal_warns_without_skipping() {
+    let path = write_input(
+        "insufficient_funds_warning.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 4.0
+withdrawal, 1, 3, 7.0
+",
+    );
+    let out = run_cli(&["--strict".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,6,0,6,false\n"
+    );
+    assert_eq!(
+        String::from_utf8(out.stderr).unwrap(),
+        "warning: rejected withdrawal tx 3 for client 1: insufficient available funds\n"
+    );
+}
+
+#[test]
+fn test_min_balance_rejects_withdrawals_below_floor() {
+    let path = write_input(
+        "min_balance.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 100.0
+withdrawal, 1, 2, 70.0
+withdrawal, 1, 3, 20.0
+",
+    );
+    let out = run_cli(&["--min-balance".as_ref(), "25".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,30,0,30,false\n"
+    );
+    assert!(String::from_utf8(out.stderr).unwrap().contains(
+        "rejected withdrawal tx 3 for client 1: withdrawal would leave available funds below the minimum balance"
+    ));
+
+    let out = run_cli(&[path.as_os_str()]);
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,10,0,10,false\n"
+    );
+}
+
+#[test]
+fn test_check_counts_rows_without_processing() {
+    let path = write_input(
+        "check.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 1, 2, ten
+refund, 1, 3, 1.0
+withdrawal, 1, 4, 99.0
+dispute, 1, 1,
+",
+    );
+    let out = run_cli(&["--check".as_ref(), path.as_os_str()]);
+    assert!(!out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "valid,malformed\n3,2\n"
+    );
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("error: 2 of 5 rows failed to parse"),
+        "{stderr}"
+    );
+    // Rows are never processed, so the overdrawn withdrawal is not reported.
+    assert!(!stderr.contains("insufficient"), "{stderr}");
+
+    let clean = write_input(
+        "check_clean.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+",
+    );
+    let out = run_cli(&["--check".as_ref(), clean.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "valid,malformed\n1,0\n"
+    );
+}
+
+#[test]
+fn test_semicolon_delimiter_with_decimal_comma() {
+    let path = write_input(
+        "semicolon_decimal_comma.csv",
+        "\
+type;client;tx;amount
+deposit;1;1;5,00
+deposit;1;2;1.234,5
+withdrawal;1;3;0,25
+deposit;2;4;7
+dispute;1;1;
+",
+    );
+    let out = run_cli(&[
+        "--delimiter".as_ref(),
+        ";".as_ref(),
+        "--decimal-comma".as_ref(),
+        path.as_os_str(),
+    ]);
+    assert!(
+        out.status.success(),
+        "{}",
+        String::from_utf8_lossy(&out.stderr)
+    );
+    assert!(out.stderr.is_empty());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1234.25,5,1239.25,false\n2,7,0,7,false\n"
+    );
+}
+
+#[test]
+fn test_decimal_comma_rejects_dot_that_is_not_a_thousands_separator() {
+    let path = write_input(
+        "decimal_comma_dots.csv",
+        "\
+type;client;tx;amount
+deposit;1;1;5.00
+deposit;1;2;1.234.567,5
+deposit;1;3;12.34,5
+deposit;1;4;2.000
+deposit;1;5;.500
+",
+    );
+    let out = run_cli(&[
+        "--delimiter".as_ref(),
+        ";".as_ref(),
+        "--decimal-comma".as_ref(),
+        path.as_os_str(),
+    ]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,1236567.5,0,1236567.5,false\n"
+    );
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    for amount in ["5.00", "12.34,5", ".500"] {
+        assert!(
+            stderr.contains(&format!(
+                "skipping row with amount `{amount}` whose `.` is not a thousands separator"
+            )),
+            "{stderr}"
+        );
+    }
+    assert!(stderr.contains("skipped 3 of 5 rows"), "{stderr}");
+}
+
+#[test]
+fn test_normalize_applies_type_case() {
+    let path = write_input(
+        "normalize.csv",
+        "\
+type,   client, tx, amount
+deposit,     1,  1,   10.5
+refund,      1,  2,    1.0
+dispute,     1,  1,
+",
+    );
+    let out = run_cli(&[
+        "normalize".as_ref(),
+        path.as_os_str(),
+        "--type-case".as_ref(),
+        "uppercase".as_ref(),
+    ]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "type,client,tx,amount\nDEPOSIT,1,1,10.5\nDISPUTE,1,1,\n"
+    );
+
+    let out = run_cli(&[
+        "normalize".as_ref(),
+        path.as_os_str(),
+        "--type-case".as_ref(),
+        "capitalized".as_ref(),
+    ]);
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "type,client,tx,amount\nDeposit,1,1,10.5\nDispute,1,1,\n"
+    );
+}
+
+#[test]
+fn test_summary_reports_saved_snapshot() {
+    let path = write_input(
+        "summary.csv",
+        "\
+type, client, tx, amount
+deposit,     1,  1,   10.0
+deposit,     2,  2,    5.5
+deposit,     2,  3,    1.0
+deposit,     3,  4,    2.0
+dispute,     2,  2,
+dispute,     3,  4,
+chargeback,  3,  4,
+",
+    );
+    let snapshot = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("summary.json");
+    let out = run_cli(&[
+        path.as_os_str(),
+        "--save-snapshot".as_ref(),
+        snapshot.as_os_str(),
+    ]);
+    assert!(out.status.success());
+
+    let out = run_cli(&["summary".as_ref(), snapshot.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "accounts,total,locked,open_disputes\n3,16.5,1,1\n"
+    );
+}
+
+#[test]
+fn test_stats_and_summary_report_overflowing_total() {
+    let path = write_input(
+        "stats_overflow.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 60000000000000000000000000000
+deposit, 2, 2, 60000000000000000000000000000
+",
+    );
+    let snapshot = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("stats_overflow.json");
+    let out = run_cli(&[
+        path.as_os_str(),
+        "--stats".as_ref(),
+        "--save-snapshot".as_ref(),
+        snapshot.as_os_str(),
+    ]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("error: sum of account totals is too large to represent"),
+        "{stderr}"
+    );
+
+    let out = run_cli(&["summary".as_ref(), snapshot.as_os_str()]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("error: sum of account totals is too large to represent"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_baseline_reports_only_changed_accounts() {
+    let baseline = write_input(
+        "baseline.csv",
+        "\
+client,available,held,total,locked
+1,10,0,10,false
+2,5,0,5,false
+",
+    );
+    let input = write_input(
+        "baseline_input.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+withdrawal, 2, 3, 1.5
+",
+    );
+    let out = run_cli(&[
+        "--baseline".as_ref(),
+        baseline.as_os_str(),
+        input.as_os_str(),
+    ]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n2,3.5,0,3.5,false\n"
+    );
+}
+
+#[test]
+fn test_multiple_input_files_share_one_engine() {
+    let whole = write_input(
+        "split_whole.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+withdrawal, 1, 3, 2.5
+dispute, 2, 2,
+chargeback, 2, 2,
+deposit, 1, 1, 99.0
+",
+    );
+    let first = write_input(
+        "split_first.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+withdrawal, 1, 3, 2.5
+",
+    );
+    let second = write_input(
+        "split_second.csv",
+        "\
+type, client, tx, amount
+dispute, 2, 2,
+chargeback, 2, 2,
+deposit, 1, 1, 99.0
+",
+    );
+
+    let single = run_cli(&[whole.as_os_str()]);
+    let split = run_cli(&[first.as_os_str(), second.as_os_str()]);
+    assert!(split.status.success());
+    assert_eq!(
+        String::from_utf8(split.stdout).unwrap(),
+        "client,available,held,total,locked\n1,7.5,0,7.5,false\n2,0,0,0,true\n"
+    );
+    assert_eq!(split.stderr, single.stderr);
+
+    let missing = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("split_missing.csv");
+    let out = run_cli(&[first.as_os_str(), missing.as_os_str()]);
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains(&format!("error: cannot open {}", missing.display())));
+}
+
+#[test]
+fn test_allow_negative_available_controls_disputes_of_spent_deposits() {
+    let path = write_input(
+        "negative_available.csv",
+        "\
+type, client, tx, amount
+deposit,    1, 1, 10.0
+withdrawal, 1, 2,  7.0
+dispute,    1, 1,
+",
+    );
+    // The spec's behavior: the full deposit is held and available goes negative.
+    let out = run_cli(&[path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,-7,10,3,false\n"
+    );
+
+    let out = run_cli(&[
+        "--allow-negative-available".as_ref(),
+        "false".as_ref(),
+        path.as_os_str(),
+    ]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,3,0,3,false\n"
+    );
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("skipping tx 1: available balance is smaller than the disputed amount"));
+}
+
+#[test]
+fn test_include_empty_clients_lists_dispute_only_clients() {
+    let path = write_input(
+        "empty_clients.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 5.0
+dispute, 2, 99,
+",
+    );
+    let out = run_cli(&[path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,5,0,5,false\n"
+    );
+
+    let out = run_cli(&[path.as_os_str(), "--include-empty-clients".as_ref()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,5,0,5,false\n2,0,0,0,false\n"
+    );
+}
+
+#[test]
+fn test_max_system_disputes_rejects_disputes_beyond_cap() {
+    let path = write_input(
+        "max_system_disputes.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 5.0
+dispute, 1, 1,
+dispute, 2, 2,
+",
+    );
+    let out = run_cli(&[
+        "--max-system-disputes".as_ref(),
+        "1".as_ref(),
+        path.as_os_str(),
+    ]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0,10,10,false\n2,5,0,5,false\n"
+    );
+    assert!(String::from_utf8(out.stderr)
+        .unwrap()
+        .contains("skipping tx 2: too many disputes are already open"));
+
+    let out = run_cli(&[
+        "--max-system-disputes".as_ref(),
+        "2".as_ref(),
+        path.as_os_str(),
+    ]);
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,0,10,10,false\n2,0,5,5,false\n"
+    );
+}
+
+#[test]
+fn test_gzipped_input_is_decompressed() {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(b"type, client, tx, amount\ndeposit, 1, 1, 10.0\nwithdrawal, 1, 2, 2.5\n")
+        .unwrap();
+    let compressed = encoder.finish().unwrap();
+    let expected = "client,available,held,total,locked\n1,7.5,0,7.5,false\n";
+
+    let by_extension = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("gzipped.csv.gz");
+    std::fs::write(&by_extension, &compressed).unwrap();
+    let out = run_cli(&[by_extension.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), expected);
+
+    let by_flag = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("gzipped.bin");
+    std::fs::write(&by_flag, &compressed).unwrap();
+    let out = run_cli(&["--gzip".as_ref(), by_flag.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8(out.stdout).unwrap(), expected);
+}
+
+#[test]
+fn test_gzipped_stdin_is_decompressed() {
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder
+        .write_all(b"type, client, tx, amount\ndeposit, 1, 1, 10.0\nwithdrawal, 1, 2, 2.5\n")
+        .unwrap();
+    let mut compressed = std::io::Cursor::new(encoder.finish().unwrap());
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_accounts_manager"))
+        .args(["-", "--gzip"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    std::io::copy(&mut compressed, &mut child.stdin.take().unwrap()).unwrap();
+
+    let out = child.wait_with_output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,7.5,0,7.5,false\n"
+    );
+}
+
+#[test]
+fn test_headerless_input_is_read_positionally() {
+    let path = write_input(
+        "headerless.csv",
+        "\
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 2.5
+deposit, 2, 3, 4.0
+",
+    );
+    let out = run_cli(&["--no-headers".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,7.5,0,7.5,false\n2,4,0,4,false\n"
+    );
+}
+
+#[test]
+fn test_multi_byte_delimiter_is_rejected() {
+    let path = write_input("bad_delimiter.csv", "type,client,tx,amount\n");
+    for delimiter in [";;", "\u{a7}", ""] {
+        let out = run_cli(&["--delimiter".as_ref(), delimiter.as_ref(), path.as_os_str()]);
+        assert!(!out.status.success());
+        assert!(
+            String::from_utf8_lossy(&out.stderr).contains("expected a single ASCII character"),
+            "{}",
+            String::from_utf8_lossy(&out.stderr)
+        );
+    }
+}
+
+#[test]
+fn test_field_order_reads_reordered_headerless_input() {
+    let path = write_input(
+        "field_order.csv",
+        "\
+1, deposit, 1, 10.0
+2, deposit, 2, 4.0
+1, withdrawal, 3, 2.5
+2, dispute, 2,
+",
+    );
+    let out = run_cli(&[
+        "--no-headers".as_ref(),
+        "--field-order".as_ref(),
+        "client,type,tx,amount".as_ref(),
+        path.as_os_str(),
+    ]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,7.5,0,7.5,false\n2,0,4,4,false\n"
+    );
+
+    let out = run_cli(&[
+        "--no-headers".as_ref(),
+        "--field-order".as_ref(),
+        "client,type,tx,client".as_ref(),
+        path.as_os_str(),
+    ]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("`client` must appear exactly once"));
+}
+
+#[test]
+fn test_precision_rounds_output_amounts() {
+    let path = write_input(
+        "precision.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.125
+deposit, 2, 2, 2.4999
+withdrawal, 2, 3, 0.0001
+",
+    );
+    let out = run_cli(&["--precision".as_ref(), "2".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,10.13,0,10.13,false\n2,2.50,0,2.50,false\n"
+    );
+
+    let out = run_cli(&["--precision".as_ref(), "0".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,10,0,10,false\n2,2,0,2,false\n"
+    );
+
+    let out = run_cli(&["--precision".as_ref(), "29".as_ref(), path.as_os_str()]);
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("precision 29"));
+}
+
+#[test]
+fn test_preserve_scale_keeps_deposit_scale() {
+    let path = write_input(
+        "preserve_scale.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 5.00
+deposit, 1, 2, 2.5
+deposit, 2, 3, 4.0
+withdrawal, 2, 4, 1.0
+",
+    );
+    let out = run_cli(&[path.as_os_str()]);
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,7.5,0,7.5,false\n2,3,0,3,false\n"
+    );
+
+    let out = run_cli(&["--preserve-scale".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,7.50,0.00,7.50,false\n2,3.0,0.0,3.0,false\n"
+    );
+}
+
+#[test]
+fn test_event_log_replays_to_same_output() {
+    let input = write_input(
+        "event_log_input.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+withdrawal, 1, 2, 50.0
+deposit, 2, 3, 7.5
+dispute, 2, 3,
+chargeback, 2, 3,
+withdrawal, 1, 4, 1.25
+",
+    );
+    let log = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("event_log.csv");
+
+    let original = run_cli(&["--event-log".as_ref(), log.as_os_str(), input.as_os_str()]);
+    assert!(original.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "type,client,tx,amount\ndeposit,1,1,10\ndeposit,2,3,7.5\ndispute,2,3,\n\
+         chargeback,2,3,\nwithdrawal,1,4,1.25\n"
+    );
+
+    let replayed = run_cli(&[log.as_os_str()]);
+    assert!(replayed.status.success());
+    assert_eq!(replayed.stdout, original.stdout);
+}
+
+#[test]
+fn test_max_memory_reports_evictions() {
+    let path = write_input(
+        "max_memory.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 1, 2, 5.0
+dispute, 1, 1,
+deposit, 1, 3, 1.0
+dispute, 1, 2,
+",
+    );
+    let out = run_cli(&["--max-memory".as_ref(), "1".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,16,0,16,false\n"
+    );
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("evicted 3 stored transactions to stay under --max-memory"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_reconcile_summary_reports_overflow() {
+    let path = write_input(
+        "reconcile_overflow.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 60000000000000000000000000000
+deposit, 2, 2, 60000000000000000000000000000
+",
+    );
+    let out = run_cli(&["--reconcile-summary".as_ref(), path.as_os_str()]);
+    assert_eq!(out.status.code(), Some(1));
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("error: sum of deposits is too large to represent"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_reconcile_summary_shows_chargeback_gap() {
+    let reconcile_line = |contents: &str, name: &str| {
+        let path = write_input(name, contents);
+        let out = run_cli(&["--reconcile-summary".as_ref(), path.as_os_str()]);
+        assert!(out.status.success());
+        String::from_utf8(out.stderr)
+            .unwrap()
+            .lines()
+            .find(|line| line.starts_with("reconcile:"))
+            .unwrap()
+            .to_owned()
+    };
+    let input = "\
+type, client, tx, amount
+deposit,    1, 1, 10.0
+deposit,    2, 2, 4.0
+withdrawal, 1, 3, 2.5
+withdrawal, 2, 4, 9.0
+dispute,    2, 2,
+";
+
+    assert_eq!(
+        reconcile_line(input, "reconcile_tied.csv"),
+        "reconcile: deposits=14 withdrawals=2.5 net=11.5 total=11.5 difference=0"
+    );
+    assert_eq!(
+        reconcile_line(
+            &format!("{input}chargeback, 2, 2,\n"),
+            "reconcile_chargeback.csv"
+        ),
+        "reconcile: deposits=14 withdrawals=2.5 net=11.5 total=7.5 difference=-4"
+    );
+}
+
+#[test]
+fn test_replay_rate_paces_without_changing_balances() {
+    let path = write_input(
+        "replay_rate.csv",
+        "\
+type, client, tx, amount
+deposit,    1, 1, 10.0
+deposit,    2, 2, 3.0
+withdrawal, 1, 3, 2.5
+dispute,    2, 2,
+deposit,    1, 4, 1.0
+",
+    );
+    let started = std::time::Instant::now();
+    let out = run_cli(&["--replay-rate".as_ref(), "200".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+    assert!(started.elapsed() >= std::time::Duration::from_millis(25));
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,8.5,0,8.5,false\n2,0,3,3,false\n"
+    );
+}
+
+#[test]
+fn test_progress_reports_rows_to_stderr_only() {
+    let path = write_input(
+        "progress.csv",
+        "\
+type, client, tx, amount
+deposit,    1, 1, 10.0
+deposit,    1, 2,
+deposit,    1, 3, 1.0
+deposit,    1, 4, 1.0
+withdrawal, 1, 5, 2.0
+",
+    );
+    let out = run_cli(&["--progress".as_ref(), "2".as_ref(), path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,10,0,10,false\n"
+    );
+
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    let progress: Vec<_> = stderr
+        .lines()
+        .filter(|line| line.starts_with("progress:"))
+        .collect();
+    assert_eq!(
+        progress,
+        [
+            "progress: 2 rows read, 1 skipped",
+            "progress: 4 rows read, 1 skipped"
+        ]
+    );
+}
+
+#[test]
+fn test_guard_system_total_aborts_on_negative_total() {
+    let path = write_input(
+        "negative_system_total.csv",
+        "\
+type, client, tx, amount
+deposit,    1, 1, 10.0
+deposit,    2, 2, 4.0
+withdrawal, 1, 3, 10.0
+dispute,    1, 1,
+chargeback, 1, 1,
+deposit,    2, 4, 100.0
+deposit,    2, 5, not-a-number
+",
+    );
+    let out = run_cli(&[path.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        String::from_utf8(out.stdout).unwrap(),
+        "client,available,held,total,locked\n1,-10,0,-10,true\n2,104,0,104,false\n"
+    );
+
+    let out = run_cli(&["--guard-system-total".as_ref(), path.as_os_str()]);
+    assert!(!out.status.success());
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("system-wide total fell to -6 at tx 1 (record 4)"),
+        "{stderr}"
+    );
+    // Reading stops at the breach, so the malformed row after it is never parsed
+    assert!(!stderr.contains("skipping malformed row"), "{stderr}");
+}
+
+#[test]
+fn test_guard_system_total_reports_overflow() {
+    let path = write_input(
+        "system_total_overflow.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 60000000000000000000000000000
+deposit, 2, 2, 60000000000000000000000000000
+",
+    );
+    let out = run_cli(&["--guard-system-total".as_ref(), path.as_os_str()]);
+    assert_eq!(out.status.code(), Some(1));
+    assert!(out.stdout.is_empty());
+    let stderr = String::from_utf8(out.stderr).unwrap();
+    assert!(
+        stderr.contains("error: sum of account totals is too large to represent"),
+        "{stderr}"
+    );
+}
+
+#[test]
+fn test_audit_log_lists_applied_chargebacks() {
+    let input = write_input(
+        "audit_log_input.csv",
+        "\
+type, client, tx, amount
+deposit, 1, 1, 10.0
+deposit, 2, 2, 7.5
+dispute, 2, 2,
+chargeback, 1, 1,
+chargeback, 2, 2,
+",
+    );
+    let log = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("audit_log.csv");
+
+    let out = run_cli(&["--audit-log".as_ref(), log.as_os_str(), input.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "client,tx,amount,sequence_index\n2,2,7.5,4\n"
+    );
+
+    // Skipped rows still count, so the index keeps pointing at the input row
+    let input = write_input(
+        "audit_log_skipped_input.csv",
+        "\
+type, client, tx, amount
+deposit, 2, 2, 7.5
+deposit, 2, 3, not-a-number
+dispute, 2, 2,
+deposit, 2, 4,
+chargeback, 2, 2,
+",
+    );
+    let out = run_cli(&["--audit-log".as_ref(), log.as_os_str(), input.as_os_str()]);
+    assert!(out.status.success());
+    assert_eq!(
+        std::fs::read_to_string(&log).unwrap(),
+        "client,tx,amount,sequence_index\n2,2,7.5,4\n"
+    );
+}
+
+#[test]
+fn test_partition_output_splits_by_client_modulo() {
+    let input = write_input(
+        "partition_input.csv",
+        "\
+type, client, tx, amount
+deposit, 4, 1, 4.0
+deposit, 1, 2, 1.0
+deposit, 3, 3, 3.0
+deposit, 2, 4, 2.0
+",
+    );
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("partitions");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let out = run_cli(&[
+        "--partition-output".as_ref(),
+        dir.as_os_str(),
+        "--partitions".as_ref(),
+        "2".as_ref(),
+        input.as_os_str(),
+    ]);
+    assert!(out.status.success());
+    assert!(out.stdout.is_empty());
+
+    assert_eq!(
+        std::fs::read_to_string(dir.join("accounts-0.csv")).unwrap(),
+        "client,available,held,total,locked\n2,2,0,2,false\n4,4,0,4,false\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("accounts-1.csv")).unwrap(),
+        "client,available,held,total,locked\n1,1,0,1,false\n3,3,0,3,false\n"
+    );
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+}
+
+#[test]
+fn test_partition_output_rejects_format_and_header_flags() {
+    let input = write_input("partition_flags_input.csv", "type, client, tx, amount\n");
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join("partition_flags");
+
+    for flags in [
+        &["--format", "json"][..],
+        &["--with-metadata"],
+        &["--with-schema-version"],
+    ] {
+        let mut args = vec![
+            "--partition-output".as_ref(),
+            dir.as_os_str(),
+            "--partitions".as_ref(),
+            "2".as_ref(),
+            input.as_os_str(),
+        ];
+        args.extend(flags.iter().map(std::ffi::OsStr::new));
+
+        let out = run_cli(&args);
+        assert_eq!(out.status.code(), Some(2), "{flags:?}");
+        let stderr = String::from_utf8(out.stderr).unwrap();
+        assert!(
+            stderr.contains(&format!("cannot be used with '{}", flags[0])),
+            "{stderr}"
+        );
+    }
+    assert!(!dir.exists());
+}